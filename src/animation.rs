@@ -0,0 +1,32 @@
+use std::error::Error;
+use plotters::prelude::*;
+
+use crate::figure::{ Backend, Figure, draw_on };
+
+//picked by the animation switches, which are off by default
+#[allow(dead_code)]
+pub enum Animation {
+    Gif(u32),//frame delay in ms
+    Frames(Backend),//numbered file per frame
+}
+
+impl Animation {
+    //fig.draw(k, canvas) is called once per frame on a cleared canvas
+    pub fn render(&self, name:&str, size:(u32, u32), frame_count:usize, fig:&impl Figure) -> Result<(), Box<dyn Error>> {
+        match self {
+            Animation::Gif(delay) => {
+                let file = format!("{}.gif", name);
+                let canvas = BitMapBackend::gif(&file, size, *delay)?.into_drawing_area();
+                for k in 0..frame_count {
+                    draw_on(&canvas, k, fig)?;
+                }
+            },
+            Animation::Frames(backend) => {
+                for k in 0..frame_count {
+                    backend.render(&format!("{}_{:04}", name, k), size, k, fig)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
\ No newline at end of file
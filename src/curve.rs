@@ -0,0 +1,165 @@
+use std::error::Error;
+use std::fs;
+use plotters::{prelude::*, coord::{Shift, CoordTranslate}};
+
+use crate::figure::Figure;
+
+//per-episode metrics of one run
+pub struct RunLog {
+    pub length:Vec<f64>,
+    pub ret:Vec<f64>,//undiscounted
+}
+
+//per episode over runs, half:half width of the 95% confidence interval of the mean
+pub struct Band {
+    pub mean:Vec<f64>,
+    pub half:Vec<f64>,
+}
+
+pub struct LearningCurve {
+    pub label:String,
+    pub run:usize,
+    pub length:Band,
+    pub ret:Band,
+    pub step:Band,//cumulative time steps
+}
+
+//episode length, return, and episodes over time steps as in Sutton & Barto figure 6.3
+pub struct LearningFigure<'a> {
+    pub curve:&'a [LearningCurve],
+}
+
+impl RunLog {
+    pub fn new() -> Self {
+        Self { length:Vec::new(), ret:Vec::new() }
+    }
+
+    pub fn push(&mut self, length:usize, ret:f64) {
+        self.length.push(length as f64);
+        self.ret.push(ret);
+    }
+}
+
+impl Band {
+    //runs are cut to the shortest one
+    fn new(sample:&[Vec<f64>]) -> Self {
+        let n = sample.len() as f64;
+        let len = sample.iter().map(|v| v.len()).min().unwrap_or(0);
+        let (mut mean, mut half) = (Vec::with_capacity(len), Vec::with_capacity(len));
+        for i in 0..len {
+            let m = sample.iter().map(|v| v[i]).sum::<f64>() / n;
+            let var = sample.iter().map(|v| (v[i] - m) * (v[i] - m)).sum::<f64>() / (n - 1.0).max(1.0);
+            mean.push(m);
+            half.push(1.96 * (var / n).sqrt());
+        }
+        Self { mean, half }
+    }
+
+    fn range(&self) -> (f64, f64) {
+        self.mean.iter().zip(self.half.iter())
+            .fold((f64::MAX, f64::MIN), |(l, h), (m, d)| (l.min(m - d), h.max(m + d)))
+    }
+}
+
+impl LearningCurve {
+    pub fn new(label:&str, log:&[RunLog]) -> Self {
+        let step:Vec<Vec<f64>> = log.iter()
+            .map(|l| l.length.iter().scan(0.0, |t, v| { *t += v; Some(*t) }).collect())
+            .collect();
+        Self {
+            label:label.to_string(), run:log.len(),
+            length:Band::new(&log.iter().map(|l| l.length.clone()).collect::<Vec<_>>()),
+            ret:Band::new(&log.iter().map(|l| l.ret.clone()).collect::<Vec<_>>()),
+            step:Band::new(&step),
+        }
+    }
+
+    fn episodes(&self) -> usize {
+        self.length.mean.len()
+    }
+}
+
+//one row per curve and episode, every metric as mean and confidence half width
+pub fn write_csv(path:&str, curve:&[LearningCurve]) -> Result<(), Box<dyn Error>> {
+    let mut text = String::from("label,runs,episode,length,length_ci,return,return_ci,step,step_ci\n");
+    for c in curve.iter() {
+        for i in 0..c.episodes() {
+            text += &format!("{},{},{},{},{},{},{},{},{}\n", c.label, c.run, i + 1,
+                c.length.mean[i], c.length.half[i], c.ret.mean[i], c.ret.half[i], c.step.mean[i], c.step.half[i]);
+        }
+    }
+    fs::write(path, text).map_err(|e| format!("failed to write {}: {}", path, e))?;
+    Ok(())
+}
+
+//mean line over a band from lo to hi
+fn draw_band<DB:DrawingBackend, CT:CoordTranslate<From = (f64, f64)>>(chart:&mut ChartContext<'_, DB, CT>, label:&str, k:usize,
+    mean:Vec<(f64, f64)>, lo:Vec<(f64, f64)>, hi:Vec<(f64, f64)>) -> Result<(), Box<dyn Error>>
+    where DB::ErrorType: 'static {
+    let color = Palette99::pick(k).to_rgba();
+    //a quad per step, one long noisy polygon trips the rasterizer
+    let band = (1..mean.len()).map(|i| Polygon::new(vec![lo[i - 1], lo[i], hi[i], hi[i - 1]], color.mix(0.2).filled()));
+    chart.draw_series(band)?;
+    chart.draw_series(LineSeries::new(mean, color.stroke_width(1)))?
+        .label(label)
+        .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color.stroke_width(2)));
+    Ok(())
+}
+
+impl<'a> Figure for LearningFigure<'a> {
+    fn draw<DB:DrawingBackend>(&self, _k:usize, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+        where DB::ErrorType: 'static {
+        let panel = canvas.split_evenly((1, 3));
+        let episodes = self.curve.iter().map(|c| c.episodes()).max().unwrap_or(0) as f64;
+        let range = |band:fn(&LearningCurve) -> &Band| self.curve.iter().map(|c| band(c).range())
+            .fold((f64::MAX, f64::MIN), |(l, h), (cl, ch)| (l.min(cl), h.max(ch)));
+        let series = |band:&Band, i:usize, sign:f64| (i as f64 + 1.0, band.mean[i] + sign * band.half[i]);
+
+        let (_, length_max) = range(|c| &c.length);
+        let mut chart = ChartBuilder::on(&panel[0])
+            .margin(10)
+            .x_label_area_size(50)
+            .y_label_area_size(60)
+            .build_cartesian_2d(1.0..episodes.max(2.0), (1.0..length_max.max(2.0) * 1.1).log_scale())?;
+        chart.configure_mesh().x_desc("episode").y_desc("episode length").draw()?;
+        for (k, c) in self.curve.iter().enumerate() {
+            let b = &c.length;
+            let point = |sign:f64| (0..c.episodes()).map(|i| { let (x, y) = series(b, i, sign); (x, y.max(1.0)) }).collect();
+            draw_band(&mut chart, &c.label, k, point(0.0), point(-1.0), point(1.0))?;
+        }
+        chart.configure_series_labels().background_style(WHITE.mix(0.8)).border_style(BLACK).draw()?;
+
+        let (ret_min, ret_max) = range(|c| &c.ret);
+        let pad = ((ret_max - ret_min) * 0.05).max(0.05);
+        let mut chart = ChartBuilder::on(&panel[1])
+            .margin(10)
+            .x_label_area_size(50)
+            .y_label_area_size(60)
+            .build_cartesian_2d(1.0..episodes.max(2.0), ret_min - pad..ret_max + pad)?;
+        chart.configure_mesh().x_desc("episode").y_desc("return").draw()?;
+        for (k, c) in self.curve.iter().enumerate() {
+            let point = |sign:f64| (0..c.episodes()).map(|i| series(&c.ret, i, sign)).collect();
+            draw_band(&mut chart, &c.label, k, point(0.0), point(-1.0), point(1.0))?;
+        }
+        chart.configure_series_labels()
+            .position(SeriesLabelPosition::LowerRight)
+            .background_style(WHITE.mix(0.8)).border_style(BLACK).draw()?;
+
+        //time steps across, the band is horizontal
+        let (_, step_max) = range(|c| &c.step);
+        let mut chart = ChartBuilder::on(&panel[2])
+            .margin(10)
+            .x_label_area_size(50)
+            .y_label_area_size(60)
+            .build_cartesian_2d(0.0..step_max.max(1.0) * 1.05, 0.0..episodes.max(1.0) * 1.05)?;
+        chart.configure_mesh().x_desc("time steps").y_desc("episodes").draw()?;
+        for (k, c) in self.curve.iter().enumerate() {
+            let point = |sign:f64| (0..c.episodes()).map(|i| { let (y, x) = series(&c.step, i, sign); (x, y) }).collect();
+            draw_band(&mut chart, &c.label, k, point(0.0), point(-1.0), point(1.0))?;
+        }
+        chart.configure_series_labels()
+            .position(SeriesLabelPosition::UpperLeft)
+            .background_style(WHITE.mix(0.8)).border_style(BLACK).draw()?;
+        Ok(())
+    }
+}
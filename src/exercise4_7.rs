@@ -3,7 +3,10 @@ use std::error::Error;
 use std::collections::BTreeMap;
 use std::collections::btree_map::Entry::{ Vacant, Occupied };
 use std::cmp::{ min, max };
+use plotters::{prelude::*, coord::Shift};
 
+use crate::animation::Animation;
+use crate::figure::Figure;
 use crate::nd_vec::{ NdVec1, NdVec2 };
 use crate::poisson::Poisson;
 
@@ -41,6 +44,17 @@ struct Policy {
     pub state_action: NdVec2<i32>,//state index - action index
 }
 
+struct Sweep {
+    pub state_v: Vec<f64>,//same layout as Graph::state
+    pub state_action: Vec<i32>,
+}
+
+struct SweepFigure<'a> {
+    pub g: &'a Graph,
+    pub gi: &'a GraphInfo,
+    pub sweep: &'a [Sweep],
+}
+
 struct StateDesc {
     pub name: String,
     pub count: (i32, i32),
@@ -170,8 +184,6 @@ impl Graph {
         let dist0 = &gi.dist_return_0;
         let dist1 = &gi.dist_return_1;
         let sr = gi.state_range;
-        let c0 = c0 as f64;
-        let c1 = c1 as f64;
         let kf = k as f64;
         let return0 = Graph::expected_count(sr, dist0);
         let return1 = Graph::expected_count(sr, dist1);
@@ -184,14 +196,12 @@ impl Graph {
 
     fn parse_action(s:&mut State) {
         let mut map:BTreeMap<i32, Vec<i32>> = BTreeMap::new();
-        let mut i = 0;
-        for t in s.transition.iter() {
+        for (i, t) in s.transition.iter().enumerate() {
             let list = match map.entry(t.action) {
                 Vacant(v) => v.insert(Vec::new()),
                 Occupied(v) => v.into_mut(),
             };
-            list.push(i);
-            i += 1;
+            list.push(i as i32);
         }
         s.action = map;
     }
@@ -218,8 +228,8 @@ impl Graph {
         for k in -m..=m {
             let desc = ActionDesc::new(Graph::action_name(k));
             let action_reward = (k.abs() - match c {
-                Some(v) => if k > 0 { v.free_shuttle } else { 0 },
-                None => 0,
+                Some(v) if k > 0 => v.free_shuttle,
+                _ => 0,
             }) as f64 * -2.0;
             self.add_action(desc, action_reward);
         }
@@ -314,7 +324,7 @@ impl Policy {
     }
 }
 
-fn evaluate_policy(g:&mut Graph, p:&Policy, info:&AgentInfo) {
+fn evaluate_policy(g:&mut Graph, p:&Policy, info:&AgentInfo, sweep:&mut Vec<Sweep>) {
     let mut i = 0;
     let pg:*const Graph = g;
     //hack to grant shared access to graph
@@ -333,6 +343,10 @@ fn evaluate_policy(g:&mut Graph, p:&Policy, info:&AgentInfo) {
             // println!("{} {} {}", s.name(), v_old, v_new);
             delta = delta.max((v_new - v_old).abs());
         }
+        sweep.push(Sweep {
+            state_v: g.state.iter().map(|s| s.state_v).collect(),
+            state_action: p.state_action.data.clone(),
+        });
         i += 1;
         // println!("{}:{}", i, delta);
         if delta <= info.theta || i >= info.max_iter { break }
@@ -379,6 +393,41 @@ fn improve_policy(p:&mut Policy, g:&Graph, info:&AgentInfo, _gi:&GraphInfo) -> b
     policy_stable
 }
 
+fn draw_heatmap<DB:DrawingBackend>(g:&Graph, gi:&GraphInfo, data:&[f64], range:(f64, f64), caption:&str, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+    where DB::ErrorType: 'static {
+    let sr = gi.state_range;
+    let mut chart = ChartBuilder::on(canvas)
+        .margin(5)
+        .caption(caption, ("sans-serif", 30))
+        .x_label_area_size(50)
+        .y_label_area_size(50)
+        .build_cartesian_2d(0..sr + 1, 0..sr + 1)?;
+    chart.configure_mesh().disable_mesh().draw()?;
+    chart.draw_series(data.iter().enumerate().map(|(i, v)| {
+        let (m, n) = g.state.rev_index(i);
+        let color = ViridisRGB::get_color_normalized(*v, range.0, range.1);
+        Rectangle::new([(m, n), (m + 1, n + 1)], color.filled())
+    }))?;
+    Ok(())
+}
+
+//one animation frame: value function and the policy being evaluated
+impl<'a> Figure for SweepFigure<'a> {
+    fn draw<DB:DrawingBackend>(&self, k:usize, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+        where DB::ErrorType: 'static {
+        let (g, gi, sweep) = (self.g, self.gi, self.sweep);
+        let (v_min, v_max) = sweep.iter().flat_map(|sw| sw.state_v.iter())
+            .fold((f64::MAX, f64::MIN), |(l, h), v| (l.min(*v), h.max(*v)));
+        let a = gi.move_limit as f64;
+        let sw = &sweep[k];
+        let action:Vec<f64> = sw.state_action.iter().map(|a| *a as f64).collect();
+        let canvas_split = canvas.split_evenly((1, 2));
+        draw_heatmap(g, gi, &sw.state_v, (v_min, v_max), &format!("value, sweep {}", k), &canvas_split[0])?;
+        draw_heatmap(g, gi, &action, (-a, a), &format!("policy, sweep {}", k), &canvas_split[1])?;
+        Ok(())
+    }
+}
+
 pub fn run() -> Result<(), Box<dyn Error>> {
     let agent_info = AgentInfo { discount:0.9, theta:0.1, max_iter:16 };
     let state_range:usize = 20;
@@ -404,8 +453,14 @@ pub fn run() -> Result<(), Box<dyn Error>> {
     g.print_reward(&g_info);
     // g.print_info(&graph_info, &p);
     let mut p = Policy::new(&g_info);
+    //animation switch
+    let animation:Option<Animation> =
+        None;
+        // Some(Animation::Gif(100));
+        // Some(Animation::Frames(crate::figure::Backend::Svg));
+    let mut sweep:Vec<Sweep> = Vec::new();
     loop {
-        evaluate_policy(&mut g, &p, &agent_info);
+        evaluate_policy(&mut g, &p, &agent_info, &mut sweep);
         // g.print_state();
         let stable = improve_policy(&mut p, &g, &agent_info, &g_info);
         // g.print_state(&g_info);
@@ -414,5 +469,9 @@ pub fn run() -> Result<(), Box<dyn Error>> {
     }
     println!("finish");
     g.print_info(&g_info, &p, agent_info.discount);
+    if let Some(animation) = animation {
+        let fig = SweepFigure { g:&g, gi:&g_info, sweep:&sweep };
+        animation.render("4_7_sweep", (1440, 720), sweep.len(), &fig)?;
+    }
     Ok(())
 }
\ No newline at end of file
@@ -2,6 +2,9 @@ use std::cmp::{ min, max };
 use std::error::Error;
 use plotters::{prelude::*, coord::Shift};
 
+use crate::animation::Animation;
+use crate::figure::{ Backend, Figure };
+
 struct Graph {
     pub state:Vec<State>,
 }
@@ -9,9 +12,14 @@ struct Graph {
 struct GraphInfo {
     pub theta:f64,
     pub p_win:f64,
-    pub state_range:i32,
-    pub state_active:(i32, i32),
+    pub discount:f64,
+    pub state_range:i32,//goal capital
+    pub reward_goal:f64,
+    pub reward_ruin:f64,
+    pub bet_cost:f64,//paid on every bet
     pub print_per_line:usize,
+    pub figure:Backend,
+    pub animation:Option<Animation>,
 }
 
 struct State {
@@ -26,6 +34,29 @@ struct Policy {
     pub action_max:i32,
 }
 
+struct Sweep {
+    pub state_v:Vec<f64>,
+    pub state_action:Vec<i32>,//greedy with state_v
+}
+
+struct ValueFigure<'a> {
+    pub g:&'a Graph,
+    pub p:&'a Policy,
+    pub gi:&'a GraphInfo,
+    pub sweep_v:&'a [Sweep],
+}
+
+struct SweepFigure<'a> {
+    pub gi:&'a GraphInfo,
+    pub sweep_v:&'a [Sweep],
+}
+
+impl GraphInfo {
+    fn state_active(&self) -> (i32, i32) {
+        (1, self.state_range - 1)
+    }
+}
+
 impl State {
     fn new(capital:i32, reward:f64) -> Self {
         Self { capital, reward, state_v:0.0 }
@@ -39,12 +70,16 @@ impl Graph {
 
     fn setup(&mut self, gi:&GraphInfo) {
         let sr = gi.state_range;
-        for k in 0..sr {
+        self.state.push(State::new(0, gi.reward_ruin));
+        for k in 1..sr {
             self.state.push(State::new(k, 0.0));
         }
-        self.state.push(State::new(sr, 1.0));
-        let s_t = self.state.last_mut().unwrap();
-        s_t.state_v = s_t.reward;
+        self.state.push(State::new(sr, gi.reward_goal));
+        //terminal states keep their reward as value
+        for k in [0, sr as usize] {
+            let s_t = &mut self.state[k];
+            s_t.state_v = s_t.reward;
+        }
     }
 
     fn expected_reward(&self, s:&State, a:i32, gi:&GraphInfo) -> f64 {
@@ -52,7 +87,13 @@ impl Graph {
         let s_lose = max(s.capital - a, 0) as usize;
         let r_win = self.state[s_win].state_v * gi.p_win;
         let r_lose = self.state[s_lose].state_v * (1.0 - gi.p_win);
-        s.reward + r_win + r_lose
+        s.reward - gi.bet_cost + gi.discount * (r_win + r_lose)
+    }
+
+    fn greedy_action(&self, s:&State, gi:&GraphInfo) -> (i32, f64) {
+        let bet_max = min(s.capital, gi.state_range - s.capital);
+        (1..=bet_max).rev().map(|a| (a, self.expected_reward(s, a, gi)))
+            .max_by(|(_, x), (_, y)| x.total_cmp(y)).unwrap()
     }
 
     // fn print_state(&self, gi:&GraphInfo) {
@@ -66,50 +107,39 @@ impl Graph {
     // }
 
     fn print_policy(&self, p:&Policy, gi:&GraphInfo) {
-        let p_v = &p.state_action;
-        for k in 0..p_v.len() {
-            print!("\t{}: {}", k, p_v[k]);
+        for (k, a) in p.state_action.iter().enumerate() {
+            print!("\t{}: {}", k, a);
             if k> 0 && k % gi.print_per_line == 0 { println!(); }
         }
         println!();
     }
 
-    fn draw_policy(&self, p:&Policy, gi:&GraphInfo, canvas:&DrawingArea<BitMapBackend, Shift>) -> Result<(), Box<dyn Error>> {
-        let (s_min, s_max) = gi.state_active;
-        let mut chart = ChartBuilder::on(canvas)
-            .margin(5)
-            .x_label_area_size(50)
-            .y_label_area_size(50)
-            .build_cartesian_2d(0..gi.state_range, 0..p.action_max)?;
-        chart.configure_mesh().draw()?;
-        chart.draw_series(LineSeries::new(
-            (s_min..s_max).map(|i| (i, p.state_action[i as usize]))
-            , &BLUE))?;
-        Ok(())
+    fn draw_policy<DB:DrawingBackend>(&self, p:&Policy, gi:&GraphInfo, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+        where DB::ErrorType: 'static {
+        draw_action(&p.state_action, p.action_max, gi, canvas)
     }
 }
 
 impl Policy {
     fn new(gi:&GraphInfo) -> Self {
-        let mut state_action:Vec<i32> = Vec::new();
-        state_action.resize((gi.state_range + 1) as usize, 0);
+        let state_action = vec![0; (gi.state_range + 1) as usize];
         Self { state_action, state_v_max:0.0, action_max:0 }
     }
 }
 
-fn value_iteration(g:&mut Graph, gi:&GraphInfo, canvas:&DrawingArea<BitMapBackend, Shift>) -> Result<(), Box<dyn Error>> {
+fn value_range(sweep_v:&[Sweep]) -> (f64, f64) {
+    let (v_min, v_max) = sweep_v.iter().flat_map(|sw| sw.state_v.iter())
+        .fold((f64::MAX, f64::MIN), |(l, h), v| (l.min(*v), h.max(*v)));
+    let pad = ((v_max - v_min) * 0.05).max(0.05);
+    (v_min - pad, v_max + pad)
+}
+
+fn value_iteration(g:&mut Graph, gi:&GraphInfo) -> Vec<Sweep> {
     let pg:*const Graph = g;
     //hack to grant shared access to graph
     let gs = unsafe { &(*pg) };
-    let (s_min, s_max) = gi.state_active;
-    let mut chart = ChartBuilder::on(canvas)
-        .margin(5)
-        .x_label_area_size(50)
-        .y_label_area_size(50)
-        .build_cartesian_2d(0..gi.state_range, -0.1f64..1.0f64)?;
-    chart.configure_mesh().draw()?;
-    let mut sweep = 0;
-    let sweep_band = 10;
+    let (s_min, s_max) = gi.state_active();
+    let mut sweep_v:Vec<Sweep> = Vec::new();
     loop {
         let mut delta:f64 = 0.0;
         for k in s_min..=s_max {
@@ -121,38 +151,114 @@ fn value_iteration(g:&mut Graph, gi:&GraphInfo, canvas:&DrawingArea<BitMapBacken
             s.state_v = v_new;
             delta = delta.max((v_new - v_old).abs());
         }
+        let mut state_action = vec![0; g.state.len()];
+        for k in s_min..=s_max {
+            let ki = k as usize;
+            (state_action[ki], _) = gs.greedy_action(&g.state[ki], gi);
+        }
+        sweep_v.push(Sweep { state_v:g.state.iter().map(|s| s.state_v).collect(), state_action });
+        // gs.print_state(&gi);
+        if delta < gi.theta { break }
+    }
+    sweep_v
+}
+
+fn draw_value<DB:DrawingBackend>(sweep_v:&[Sweep], gi:&GraphInfo, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+    where DB::ErrorType: 'static {
+    let (s_min, s_max) = gi.state_active();
+    let (v_min, v_max) = value_range(sweep_v);
+    let mut chart = ChartBuilder::on(canvas)
+        .margin(5)
+        .x_label_area_size(50)
+        .y_label_area_size(50)
+        .build_cartesian_2d(0..gi.state_range, v_min..v_max)?;
+    chart.configure_mesh().draw()?;
+    let sweep_band = 10;
+    for (sweep, sw) in sweep_v.iter().enumerate() {
         let sweepf = ((1.0f32).min(sweep as f32 / sweep_band as f32) * 255.0).round() as u8;
-        let color = if sweep % 2 == 0 { RGBColor { 0:sweepf, 1:128, 2:128 } }
-            else { RGBColor { 0:128, 1:sweepf, 2:128 } };
+        let color = if sweep % 2 == 0 { RGBColor(sweepf, 128, 128) }
+            else { RGBColor(128, sweepf, 128) };
         let line = chart.draw_series(LineSeries::new(
-            (s_min..s_max).map(|i| (i, g.state[i as usize].state_v))
+            (s_min..=s_max).map(|i| (i, sw.state_v[i as usize]))
             , &color))?;
         if sweep < sweep_band {
             line.label(format!("sweep {}", sweep))
             .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
         }
-        // gs.print_state(&gi);
-        sweep += 1;
-        if delta < gi.theta { break }
     }
     chart
         .configure_series_labels()
-        .background_style(&WHITE.mix(0.8))
-        .border_style(&BLACK)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
         .draw()?;
     Ok(())
 }
 
+fn draw_action<DB:DrawingBackend>(state_action:&[i32], action_max:i32, gi:&GraphInfo, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+    where DB::ErrorType: 'static {
+    let (s_min, s_max) = gi.state_active();
+    let mut chart = ChartBuilder::on(canvas)
+        .margin(5)
+        .x_label_area_size(50)
+        .y_label_area_size(50)
+        .build_cartesian_2d(0..gi.state_range, 0..max(action_max, 1))?;
+    chart.configure_mesh().draw()?;
+    chart.draw_series(LineSeries::new(
+        (s_min..=s_max).map(|i| (i, state_action[i as usize]))
+        , &BLUE))?;
+    Ok(())
+}
+
+//one animation frame: sweeps so far with the current one highlighted, and its greedy policy
+fn draw_sweep<DB:DrawingBackend>(sweep_v:&[Sweep], k:usize, gi:&GraphInfo, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+    where DB::ErrorType: 'static {
+    let (s_min, s_max) = gi.state_active();
+    let (v_min, v_max) = value_range(sweep_v);
+    let action_max = sweep_v.iter().flat_map(|sw| sw.state_action.iter()).copied().max().unwrap_or(1);
+    let canvas_split = canvas.split_evenly((2, 1));
+    let mut chart = ChartBuilder::on(&canvas_split[0])
+        .margin(5)
+        .caption(format!("sweep {}", k), ("sans-serif", 30))
+        .x_label_area_size(50)
+        .y_label_area_size(50)
+        .build_cartesian_2d(0..gi.state_range, v_min..v_max)?;
+    chart.configure_mesh().draw()?;
+    for sw in sweep_v[..k].iter() {
+        chart.draw_series(LineSeries::new(
+            (s_min..=s_max).map(|i| (i, sw.state_v[i as usize]))
+            , &BLACK.mix(0.2)))?;
+    }
+    chart.draw_series(LineSeries::new(
+        (s_min..=s_max).map(|i| (i, sweep_v[k].state_v[i as usize]))
+        , &RED))?;
+    draw_action(&sweep_v[k].state_action, action_max, gi, &canvas_split[1])
+}
+
+impl<'a> Figure for ValueFigure<'a> {
+    fn draw<DB:DrawingBackend>(&self, _k:usize, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+        where DB::ErrorType: 'static {
+        let canvas_split = canvas.split_evenly((2, 1));
+        draw_value(self.sweep_v, self.gi, &canvas_split[0])?;
+        self.g.draw_policy(self.p, self.gi, &canvas_split[1])
+    }
+}
+
+impl<'a> Figure for SweepFigure<'a> {
+    fn draw<DB:DrawingBackend>(&self, k:usize, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+        where DB::ErrorType: 'static {
+        draw_sweep(self.sweep_v, k, self.gi, canvas)
+    }
+}
+
 fn check_policy(p:&mut Policy, g:&Graph, gi:&GraphInfo) {
-    let (s_min, s_max) = gi.state_active;
-    let mut v_max:f64 = 0.0;
+    let (s_min, s_max) = gi.state_active();
+    let mut v_max:f64 = f64::MIN;
     let mut a_max = 0;
     for k in s_min..=s_max {
         let ki = k as usize;
         let s = &g.state[ki];
         let bet_max = min(s.capital, gi.state_range - s.capital);
-        let (a, v) = (1..=bet_max).rev().map(|a| (a, g.expected_reward(s, a, gi)))
-            .max_by(|(_, x), (_, y)| x.total_cmp(y)).unwrap();
+        let (a, v) = g.greedy_action(s, gi);
         println!("{} {}|{:.4}", k, a, s.state_v);
         (1..=bet_max).map(|a| (a, g.expected_reward(s, a, gi)))
             .for_each(|(a, v)| println!("{}:{}", a, v));
@@ -169,20 +275,29 @@ pub fn run() -> Result<(), Box<dyn Error>> {
     let g_info = GraphInfo {
         theta: 0.001,
         p_win: 0.55,
+        discount: 1.0,
         state_range: 100,
-        state_active: (1, 99),
+        reward_goal: 1.0,
+        reward_ruin: 0.0,
+        bet_cost: 0.0,
         print_per_line: 10,
+        figure: Backend::Png,
+        animation: None,// Some(Animation::Gif(50)),
     };
+    if g_info.state_range < 2 {
+        return Err(format!("invalid goal {}", g_info.state_range).into())
+    }
     g.setup(&g_info);
     let mut p = Policy::new(&g_info);
-    let file = format!("4_9_p{}.png", g_info.p_win);
-    let canvas = BitMapBackend::new(&file, (1440, 1440)).into_drawing_area();
-    canvas.fill(&WHITE)?;
-    let canvas_split = canvas.split_evenly((2, 1));
-    value_iteration(&mut g, &g_info, &canvas_split[0])?;
+    let sweep_v = value_iteration(&mut g, &g_info);
     check_policy(&mut p, &g, &g_info);
     g.print_policy(&p, &g_info);
-    g.draw_policy(&p, &g_info, &canvas_split[1])?;
-    canvas.present()?;
+    let name = format!("4_9_p{}_g{}", g_info.p_win, g_info.state_range);
+    let fig = ValueFigure { g:&g, p:&p, gi:&g_info, sweep_v:&sweep_v };
+    g_info.figure.render(&name, (1440, 1440), 0, &fig)?;
+    if let Some(animation) = &g_info.animation {
+        let fig = SweepFigure { gi:&g_info, sweep_v:&sweep_v };
+        animation.render(&format!("{}_sweep", name), (720, 720), sweep_v.len(), &fig)?;
+    }
     Ok(())
 }
\ No newline at end of file
@@ -1,465 +1,511 @@
 use std::cmp::{ min, max };
-use std::collections::{BTreeMap, HashSet};
-use std::collections::btree_map::Entry::{ Vacant, Occupied };
+use std::collections::HashSet;
 use std::error::Error;
+use std::fs;
+use std::thread;
 use std::time::Instant;
 use rand::prelude::*;
+use rand::rngs::StdRng;
+use plotters::{prelude::*, coord::Shift};
 
-type Vec2 = (i32, i32);
-type State = (Vec2, Vec2);
-type Action = Vec2;
-// type StateAction = (State, Action);
-// type EpisodeStep = (State, Action, i32);
+use crate::curve::{ RunLog, LearningCurve, LearningFigure, write_csv };
+use crate::figure::{ Backend, Figure };
+use crate::racetrack::{ Vec2, State, Action, Cell, Field, TrackInfo, Racetrack };
 
-struct Field {
-    pub boundary:Vec<Vec2>,//index:y, value:(x_min, x_max)
-    pub start_line:i32,//min-y-row
-    pub finish_line:i32,//max-x-column
-    pub corner:i32,
+#[derive(Clone, Copy, Debug)]
+enum Estimator {
+    Weighted,
+    WeightedTruncated,//discounting-aware weighted, flat returns truncated at horizon
+    Ordinary,
+    PerDecision,
+    DiscountingAware,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Algorithm {
+    OffPolicy,//epsilon-greedy b, greedy pi
+    OnPolicyFirstVisit,//epsilon-soft
+    OnPolicyEveryVisit,
+    ExploringStarts,//random first state and action, greedy afterwards
+}
+
+#[derive(Clone, Copy)]
 struct ControlInfo {
     pub max_episode:usize,
     pub episode_check:usize,
     pub epsilon:f64,
     pub gamma:f64,
     pub horizon:usize,
-    pub estimator:i32,
-    pub field:i32,
+    pub algorithm:Algorithm,
+    pub estimator:Estimator,
+    pub field:i32,//0:load track_file
+    pub track_file:&'static str,
+    pub seed:u64,
+    pub thread:usize,//rollout threads, results depend on it as on seed, 0:all cores of this host
+    pub batch:usize,//episodes per thread between merges, results depend on it too
+    pub step_max:usize,//episodes are cut after this many steps
+    pub crash_clear:bool,//off-policy only, drop the steps before a crash from the episode
+    pub eval_run:usize,//greedy runs per start cell with velocity noise
+    pub eval_step_max:usize,//a greedy run longer than this counts as failed
+    pub figure:Option<Backend>,//greedy trajectories from every start cell
+    pub figure_per_check:bool,//one numbered image per check instead of overwriting
+    pub checkpoint:Option<&'static str>,//written every check
+    pub resume:bool,//continue from checkpoint
 }
 
 struct AgentInfo {
-    pub velocity_max:i32,
     pub action:Action,
-    pub a_space:(i32, f32),
-    pub step_reward:f64,
-    pub p_vel_inc0:f64,
 }
 
-struct Agent<'a> {
-    pub info:&'a AgentInfo,
-    pub velocity:Vec2,
-    pub position:Vec2,
+//dense indexing of (x, y, vx, vy) and actions, from the field size and velocity_max
+#[derive(Clone, Copy)]
+struct Space {
+    pub size:Vec2,
+    pub velocity_max:i32,
+    pub a_min:i32,
+    pub a_range:i32,
 }
 
 struct Episode {
-    rng:ThreadRng,
     pub state:Vec<State>,
     pub action:Vec<Action>,
+    pub reward:Vec<f64>,//reward[k]:after taking action[k] in state[k]
+    pub prob:Vec<f64>,//b(a|s) of action[k] when it was sampled, later updates of b do not change it
+    pub length:usize,//whole rollout, also the steps dropped by crash_clear
+    pub ret:f64,//undiscounted, whole rollout
+}
+
+//generates episodes on its own thread, with its own seeded rngs
+struct Worker<'a> {
+    env:Racetrack<'a>,
+    rng:StdRng,
+    cell:Vec<Vec2>,//start and track cells for exploring starts
+    ep:Vec<Episode>,
+    n:usize,//episodes of ep generated by the last batch
 }
 
 struct Graph<'a> {
     pub g:f64,
     pub w:f64,
-    pub q:BTreeMap<State, BTreeMap<Action, ActionValue>>,
+    pub q:Vec<Option<ActionValue>>,//index:state * action_count + action, None until visited
     pub p_ref:&'a mut Policy,
 }
 
 struct ActionValue {
     pub v:f64,//value
-    pub w:f64,//weight
+    pub w:f64,//weight, visit count for ordinary estimators
 }
 
 struct Policy {
-    state_action:BTreeMap<State, Action>,
+    space:Space,
+    corner:i32,//unvisited states go up before it, right after it
+    state_action:Vec<Option<Action>>,
 }
 
-impl Field {
-    fn new() -> Self {
-        Self { boundary:Vec::new(), start_line:0, finish_line:0, corner:0 }
-    }
-
-    fn append_row(&mut self, x_range:(i32, i32), y_range:i32) -> &mut Self {
-        // self.finish_line = max(x_range.1, self.finish_line);
-        for _ in 0..y_range {
-            self.boundary.push(x_range);
-        }
-        self
-    }
-
-    fn setup_v1(&mut self) {
-        self.append_row((3, 8), 3)
-            .append_row((2, 8), 6)
-            .append_row((1, 8), 8)
-            .append_row((0, 8), 7)
-            .append_row((0, 9), 1)
-            .append_row((0, 16), 2)
-            .append_row((1, 16), 1)
-            .append_row((2, 16), 2)
-            .append_row((3, 16), 1);
-        self.finish_line = 16;
-        self.corner = 25;
-    }
-
-    fn setup_v2(&mut self) {
-        self.append_row((0, 23), 3);
-        for k in 1..=15 {
-            self.append_row((k, 23), 1);
-        }
-        self.append_row((15, 24), 1)
-            .append_row((15, 26), 1)
-            .append_row((15, 27), 1)
-            .append_row((15, 30), 1)
-            .append_row((14, 32), 1)
-            .append_row((13, 32), 1)
-            .append_row((12, 32), 4)
-            .append_row((13, 32), 1)
-            .append_row((14, 32), 1)
-            .append_row((17, 32), 1);
-        self.finish_line = 32;
-        self.corner = 20;
-    }
-
-    fn random_start(&self, rng:&mut ThreadRng) -> Vec2 {
-        let start = &self.boundary[self.start_line as usize];
-        let r = rng.gen_range(start.0..=start.1);
-        (r, self.start_line)
-    }
-
-    fn indexed_start(&self, r:usize) -> Vec2 {
-        let start = &self.boundary[self.start_line as usize];
-        let r = start.0 + r as i32 % (1 + start.1 - start.0);
-        (r, self.start_line)
-    }
-
-    fn is_outside(&self, p:&Vec2) -> (bool, bool) {
-        let row = match self.boundary.get(p.1 as usize) {
-            Some(v) => v,
-            None => return (true, false),
-        };
-        if p.0 > self.finish_line { (true, true) }
-        else { (p.0 < row.0 || p.0 > row.1, false) }
-    }
-
-    fn intersect(&self, p_s:&Vec2, p:&Vec2, v:&Vec2) -> (bool, bool) {
-        let result = self.is_outside(p);
-        if result.0 { result }
-        else {
-            let step = max(v.0, v.1);
-            let v_x0 = p_s.0 as f32;
-            let v_y0 = p_s.1 as f32;
-            let v_x = v.0 as f32 / step as f32;
-            let v_y = v.1 as f32 / step as f32;
-            for i in 1..step {
-                let x = (v_x0 + v_x * i as f32).floor() as i32;
-                let y = (v_y0 + v_y * i as f32).floor() as i32;
-                let p = (x, y);
-                let result = self.is_outside(&p);
-                if result.0 { return result }
-            }
-            (false, false)
-        }
+struct Evaluation {
+    pub run:usize,
+    pub success:usize,
+    pub crash:usize,
+    pub step:Vec<usize>,//steps of the successful runs
+    pub trajectory:Vec<Vec<State>>,//first run from each start cell
+}
+
+impl Space {
+    fn new(f:&Field, t_info:&TrackInfo, a_info:&AgentInfo) -> Self {
+        Self { size:f.cell.dimension, velocity_max:t_info.velocity_max, a_min:a_info.action.0,
+            a_range:a_info.action.1 - a_info.action.0 + 1 }
     }
 
-    fn reset_to_start(&self, p:&mut Vec2, v:&mut Vec2, rng:&mut ThreadRng) {
-        *v = (0, 0);
-        *p = self.random_start(rng);
+    fn state_count(&self) -> usize {
+        let nv = 2 * self.velocity_max + 1;
+        (self.size.0 * self.size.1 * nv * nv) as usize
     }
 
-    fn print(&self) {
-        for (i, (x_min, x_max)) in self.boundary.iter().enumerate().rev() {
-            for _ in 0..*x_min {
-                print!("       ");
-            }
-            for k in *x_min..=*x_max {
-                print!("|{:02},{:02}|", k, i);
-            }
-            println!();
-        }
+    fn action_count(&self) -> usize {
+        (self.a_range * self.a_range) as usize
     }
-}
 
-impl AgentInfo {
-    fn setup(&mut self) {
-        let r = self.action.1 - self.action.0 + 1;
-        self.a_space = (r, (r * r - 1) as f32);
+    fn state(&self, s:&State) -> usize {
+        let ((x, y), (vx, vy)) = *s;
+        let (v_max, nv) = (self.velocity_max, 2 * self.velocity_max + 1);
+        debug_assert!(x >= 0 && y >= 0 && x < self.size.0 && y < self.size.1, "position {:?} outside the field", s.0);
+        debug_assert!(vx.abs() <= v_max && vy.abs() <= v_max, "velocity {:?} over velocity_max", s.1);
+        (((x * self.size.1 + y) * nv + vx + v_max) * nv + vy + v_max) as usize
     }
-}
 
-impl<'a> Agent<'a> {
-    fn new(info:&'a AgentInfo) -> Self {
-        Self { velocity:(0, 0), position:(0, 0), info }
+    //same order as comparing the action tuples
+    fn action(&self, a:&Action) -> usize {
+        let in_range = |v:i32| v >= self.a_min && v < self.a_min + self.a_range;
+        debug_assert!(in_range(a.0) && in_range(a.1), "action {:?} out of range", a);
+        ((a.0 - self.a_min) * self.a_range + a.1 - self.a_min) as usize
     }
 
-    fn action(&mut self, v_a:&Action) -> (&mut Vec2, &mut Vec2) {
-        let v_max = self.info.velocity_max;
-        let v_min = -v_max;
-        let v = &mut self.velocity;
-        v.0 = min(max(v.0 + v_a.0, v_min), v_max);
-        v.1 = min(max(v.1 + v_a.1, v_min), v_max);
-        let p = &mut self.position;
-        p.0 = p.0 + v.0;
-        p.1 = p.1 + v.1;
-        (p, v)
+    fn rev_action(&self, i:usize) -> Action {
+        let i = i as i32;
+        (i / self.a_range + self.a_min, i % self.a_range + self.a_min)
     }
 
-    fn state(&self) -> State {
-        (self.position, self.velocity)
+    fn header(&self) -> [i32; 5] {
+        [self.size.0, self.size.1, self.velocity_max, self.a_min, self.a_range]
     }
 }
 
 impl Episode {
     fn new() -> Self {
-        Self { state:Vec::new(), action:Vec::new(), rng:rand::thread_rng() }
-    }
-
-    fn step(&mut self, b:&mut Policy, f:&Field, a:&mut Agent, c_info:&ControlInfo) -> (State, Action, bool) {
-        let rng = &mut self.rng;
-        let s = a.state();
-        let info = a.info;
-        let a_min = info.action.0;
-        let (act_r, act_s)= info.a_space;
-        let v0 = -(s.1).0;
-        let v1 = -(s.1).1;
-        let v_zero = v0 == 0 && v1 == 0;
-        let act:Action;
-        let r:f64 = rng.gen();
-        if r < info.p_vel_inc0 && !v_zero {
-            act = (0, 0);
-        }
-        else {
-            let r:f64 = rng.gen();
-            if r < c_info.epsilon {
-                //equiprobable explore
-                let mut aa = (rng.gen::<f32>() * act_s) as i32;
-                let skip = v0 - a_min + (v1 - a_min) * act_r;
-                if aa >= skip {//velocity will become (0, 0)
-                    aa += 1;
-                }
-                act = (aa % act_r + a_min, aa / act_r + a_min);
-            }
-            else {
-                //greedy with policy
-                act = match b.state_action.get(&s) {
-                    Some(v) => *v,
-                    None => {
-                        let y = (s.0).1;
-                        if y > f.corner { (1, 0) }
-                        else { (0, 1) }
-                    },
-                };
-            }
-        }
-        let p_s = a.position;
-        let (p, v) = a.action(&act);
-        let (outside, finish) = f.intersect(&p_s, p, v);
-        if outside && !finish {
-            f.reset_to_start(p, v, rng);
-            //clear previous failed trajectory
-            //but keeps the boundary state for feedback
-            self.state.clear();
-            self.action.clear();
-        }
-        (s, act, finish)
+        Self { state:Vec::new(), action:Vec::new(), reward:Vec::new(), prob:Vec::new(), length:0, ret:0.0 }
     }
 
-    fn generate(&mut self, i:usize, b:&mut Policy, f:&Field, a:&mut Agent, c_info:&ControlInfo) {
+    fn generate(&mut self, i:usize, b:&Policy, env:&mut Racetrack, c_info:&ControlInfo, rng:&mut StdRng) {
+        env.reset_at(env.field.indexed_start(i));
+        self.rollout(None, b, env, c_info, rng);
+    }
+
+    //random state and action first, then greedy with b
+    fn generate_es(&mut self, cell:&[Vec2], b:&Policy, env:&mut Racetrack, c_info:&ControlInfo, rng:&mut StdRng) {
+        let v_max = env.info.velocity_max;
+        let p = *cell.choose(rng).unwrap();
+        let v = (rng.gen_range(-v_max..=v_max), rng.gen_range(-v_max..=v_max));
+        env.reset_to((p, v));
+        let act = b.space.rev_action(rng.gen_range(0..b.space.action_count()));
+        let greedy = ControlInfo { epsilon:0.0, ..*c_info };
+        self.rollout(Some(act), b, env, &greedy, rng);
+    }
+
+    //from the current env state, act0 replaces the first action of b
+    fn rollout(&mut self, mut act0:Option<Action>, b:&Policy, env:&mut Racetrack, c_info:&ControlInfo, rng:&mut StdRng) {
+        let mut s = env.state();
         self.state.clear();
         self.action.clear();
-        a.velocity = (0, 0);
-        a.position = f.indexed_start(i);
-        let mut finish = false;
-        while !finish {
-            let s:State;
-            let act:Action;
-            (s, act, finish) = self.step(b, f, a, c_info);
+        self.reward.clear();
+        self.prob.clear();
+        self.length = 0;
+        self.ret = 0.0;
+        for _ in 0..c_info.step_max {
+            let (act, p) = match act0.take() {
+                Some(v) => (v, 1.0 / b.space.action_count() as f64),
+                None => {
+                    let a = b.select_action(&s, c_info, rng);
+                    (a, b.probability(&s, &a, c_info))
+                },
+            };
+            let crash = env.crash;
+            let (ss, r, done) = env.step(&act);
+            self.length += 1;
+            self.ret += r;
+            //on-policy and exploring starts returns keep the crash penalties
+            let clear = c_info.crash_clear && c_info.algorithm == Algorithm::OffPolicy;
+            if clear && env.crash > crash && !done {
+                //clear previous failed trajectory
+                //but keeps the boundary state for feedback
+                self.state.clear();
+                self.action.clear();
+                self.reward.clear();
+                self.prob.clear();
+            }
             self.state.push(s);
             self.action.push(act);
-            // println!("{:?}:{:?}->{:?}", s, act, a.state());
+            self.reward.push(r);
+            self.prob.push(p);
+            // println!("{:?}:{:?}->{:?}", s, act, ss);
+            if done { break }
+            s = ss;
         }
         // println!("episode generated");
     }
 }
 
+impl<'a> Worker<'a> {
+    fn new(f:&'a Field, t_info:&'a TrackInfo, seed:u64, batch:usize) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let env = Racetrack::new(f, t_info, rng.gen());
+        let mut cell = f.cells(Cell::Start);
+        cell.append(&mut f.cells(Cell::Track));
+        Self { env, rng, cell, ep:(0..batch).map(|_| Episode::new()).collect(), n:0 }
+    }
+
+    //n episodes i0.. following b
+    fn generate(&mut self, i0:usize, n:usize, b:&Policy, c_info:&ControlInfo) {
+        self.n = min(n, self.ep.len());
+        for (k, ep) in self.ep[..self.n].iter_mut().enumerate() {
+            match c_info.algorithm {
+                Algorithm::ExploringStarts => ep.generate_es(&self.cell, b, &mut self.env, c_info, &mut self.rng),
+                _ => ep.generate(i0 + k, b, &mut self.env, c_info, &mut self.rng),
+            }
+        }
+    }
+}
+
 impl<'a> Graph<'a> {
     fn new(p_ref:&'a mut Policy) -> Self {
-        Self { q:BTreeMap::new(), g:0.0, w:1.0, p_ref }
+        let space = p_ref.space;
+        let q = (0..space.state_count() * space.action_count()).map(|_| None).collect();
+        Self { q, g:0.0, w:1.0, p_ref }
     }
 
-    fn mc_control(&mut self, ep:&Episode, a_info:&AgentInfo, c_info:&ControlInfo, b:Option<&Graph>) {
+    //off_policy:ratios against the b(a|s) recorded in ep, otherwise 1
+    fn mc_control(&mut self, ep:&Episode, c_info:&ControlInfo, off_policy:bool) {
         self.g = 0.0;
         self.w = 1.0;
         match c_info.estimator {
-            //weighted importance sampling
-            0 => self.mc_control_wis(ep, a_info, c_info, b),
-            //weighted truncated importance sampling
-            1 => self.mc_control_wtis(ep, a_info, c_info, b),
-            _ => todo!()
+            Estimator::Weighted => self.mc_control_wis(ep, c_info, off_policy),
+            Estimator::WeightedTruncated => self.mc_control_wtis(ep, c_info, off_policy),
+            Estimator::Ordinary => self.mc_control_ois(ep, c_info, off_policy),
+            Estimator::PerDecision => self.mc_control_pdis(ep, c_info, off_policy),
+            Estimator::DiscountingAware => self.mc_control_dais(ep, c_info, off_policy),
         }
     }
 
-    fn mc_control_wis(&mut self, ep:&Episode, a_info:&AgentInfo, c_info:&ControlInfo, b:Option<&Graph>) {
+    //sample averages of the returns following (s, a), only its first occurrence when first_visit
+    fn mc_control_on(&mut self, ep:&Episode, c_info:&ControlInfo, first_visit:bool) {
+        let mut visit:HashSet<(State, Action)> = HashSet::new();
+        let first:Vec<bool> = ep.state.iter().zip(ep.action.iter())
+            .map(|(s, a)| visit.insert((*s, *a)))
+            .collect();
+        self.g = 0.0;
         for k in (0..ep.state.len()).rev() {
             let s = &ep.state[k];
             let a = &ep.action[k];
-            let r = k as f64 * a_info.step_reward;
-            self.g += r;
-            let a_map = match self.q.entry(*s) {
-                Vacant(v) => v.insert(BTreeMap::new()),
-                Occupied(v) => v.into_mut(),
-            };
-            let q = match a_map.entry(*a) {
-                Vacant(v) => v.insert(ActionValue::new()),
-                Occupied(v) => v.into_mut(),
-            };
-            q.w += self.w;
-            q.v += self.w * (self.g - q.v) / q.w;
-            let a_match = match self.improve_policy(s) {
-                Some(v) => v == a,
-                None => false,
-            };
-            match b {
-                Some(v) => {
-                    if !a_match { return }
-                    self.w *= 1.0 / v.p_epsilon(s, a, c_info);
+            self.g = ep.reward[k] + c_info.gamma * self.g;
+            if first_visit && !first[k] { continue }
+            let g = self.g;
+            let q = self.q_entry(s, a);
+            q.w += 1.0;
+            q.v += (g - q.v) / q.w;
+            self.improve_policy(s);
+        }
+    }
+
+    //q with its weights, then the policy as action index + 1, 0 when unset
+    fn save(&self, out:&mut Vec<u8>) {
+        for q in self.q.iter() {
+            match q {
+                Some(q) => {
+                    out.push(1);
+                    out.extend(q.v.to_le_bytes());
+                    out.extend(q.w.to_le_bytes());
                 },
-                None => {}
+                None => out.push(0),
             }
         }
+        let space = &self.p_ref.space;
+        out.extend(self.p_ref.state_action.iter().map(|a| a.map_or(0, |a| space.action(&a) as u8 + 1)));
+    }
+
+    fn load(&mut self, data:&mut &[u8]) -> Result<(), Box<dyn Error>> {
+        for q in self.q.iter_mut() {
+            *q = match take(data, 1)?[0] {
+                0 => None,
+                _ => Some(ActionValue { v:take_f64(data)?, w:take_f64(data)? }),
+            };
+        }
+        let space = self.p_ref.space;
+        for a in self.p_ref.state_action.iter_mut() {
+            *a = match take(data, 1)?[0] {
+                0 => None,
+                k => Some(space.rev_action(k as usize - 1)),
+            };
+        }
+        Ok(())
+    }
+
+    fn q_entry(&mut self, s:&State, a:&Action) -> &mut ActionValue {
+        let space = &self.p_ref.space;
+        let i = space.state(s) * space.action_count() + space.action(a);
+        self.q[i].get_or_insert_with(ActionValue::new)
+    }
+
+    //visited actions of s
+    fn q_state(&self, s:&State) -> impl Iterator<Item = (Action, &ActionValue)> + '_ {
+        let space = self.p_ref.space;
+        let n = space.action_count();
+        let i = space.state(s) * n;
+        self.q[i..i + n].iter().enumerate()
+            .filter_map(move |(k, q)| q.as_ref().map(|q| (space.rev_action(k), q)))
+    }
+
+    //pi(a|s)/b(a|s) at step k with pi greedy after improving it at s, 1 when learning on-policy
+    fn rho(&mut self, ep:&Episode, k:usize, off_policy:bool) -> f64 {
+        let a = &ep.action[k];
+        let a_match = match self.improve_policy(&ep.state[k]) {
+            Some(v) => v == a,
+            None => false,
+        };
+        let p = ep.prob[k];
+        if !off_policy { 1.0 }
+        else if a_match && p > 0.0 { 1.0 / p }
+        else { 0.0 }
+    }
+
+    fn mc_control_wis(&mut self, ep:&Episode, c_info:&ControlInfo, off_policy:bool) {
+        for k in (0..ep.state.len()).rev() {
+            let s = &ep.state[k];
+            let a = &ep.action[k];
+            self.g = ep.reward[k] + c_info.gamma * self.g;
+            let (w, g) = (self.w, self.g);
+            let q = self.q_entry(s, a);
+            q.w += w;
+            q.v += w * (g - q.v) / q.w;
+            let rho = self.rho(ep, k, off_policy);
+            //later weights are all 0
+            if rho == 0.0 { return }
+            self.w *= rho;
+        }
     }
 
-    fn mc_control_wtis(&mut self, ep:&Episode, a_info:&AgentInfo, c_info:&ControlInfo, b:Option<&Graph>) {
-        let mut p_vec:Vec<f64> = Vec::new();
-        for (t, s) in ep.state.iter().enumerate() {
-            //TODO: only valid for when Some(b)
-            p_vec.push(1.0 / self.p_epsilon(s, &ep.action[t], c_info));
+    fn mc_control_ois(&mut self, ep:&Episode, c_info:&ControlInfo, off_policy:bool) {
+        for k in (0..ep.state.len()).rev() {
+            let s = &ep.state[k];
+            let a = &ep.action[k];
+            self.g = ep.reward[k] + c_info.gamma * self.g;
+            //zero weighted returns still count toward the average
+            let (w, g) = (self.w, self.g);
+            let q = self.q_entry(s, a);
+            q.w += 1.0;
+            q.v += (w * g - q.v) / q.w;
+            self.w *= self.rho(ep, k, off_policy);
         }
+    }
+
+    //G = R + gamma * rho * G, rho of the following step
+    fn mc_control_pdis(&mut self, ep:&Episode, c_info:&ControlInfo, off_policy:bool) {
+        let gamma = c_info.gamma;
+        let mut rho = 1.0;
+        for k in (0..ep.state.len()).rev() {
+            let s = &ep.state[k];
+            let a = &ep.action[k];
+            self.g = ep.reward[k] + gamma * rho * self.g;
+            let g = self.g;
+            let q = self.q_entry(s, a);
+            q.w += 1.0;
+            q.v += (g - q.v) / q.w;
+            rho = self.rho(ep, k, off_policy);
+        }
+    }
+
+    //ordinary discounting-aware estimator over flat partial returns, computed backward:
+    //x = sum_h c_h * rho_(t+1:h-1) * flat_g_(t:h), y = sum_h c_h * rho_(t+1:h-1),
+    //c_h = (1 - gamma) * gamma^(h-t-1) before the episode end, gamma^(T-t-1) at the end
+    fn mc_control_dais(&mut self, ep:&Episode, c_info:&ControlInfo, off_policy:bool) {
+        let gamma = c_info.gamma;
         let tt = ep.state.len();
-        let h = c_info.horizon;
-        let gamma_v = c_info.gamma;
-        let mut gamma = 1.0;
-        let neg_gamma = 1.0 - gamma;
+        let (mut x, mut y, mut rho) = (0.0, 0.0, 1.0);
         for k in (0..tt).rev() {
             let s = &ep.state[k];
             let a = &ep.action[k];
-            let r = k as f64 * a_info.step_reward;
-            self.g += r;
-            let a_map = match self.q.entry(*s) {
-                Vacant(v) => v.insert(BTreeMap::new()),
-                Occupied(v) => v.into_mut(),
-            };
-            let q = match a_map.entry(*a) {
-                Vacant(v) => v.insert(ActionValue::new()),
-                Occupied(v) => v.into_mut(),
-            };
-            let mut w = self.w * gamma;
-            let mut g = w * self.g;
-            let mut w_h = 1.0;
-            let mut g_h = 0.0;
+            let r = ep.reward[k];
+            let c = if k + 1 < tt { 1.0 - gamma } else { 1.0 };
+            x = c * r + gamma * rho * (r * y + x);
+            y = c + gamma * rho * y;
+            let q = self.q_entry(s, a);
+            q.w += 1.0;
+            q.v += (x - q.v) / q.w;
+            rho = self.rho(ep, k, off_policy);
+        }
+    }
+
+    //discounting-aware weighted estimator: flat partial returns for the first horizon steps,
+    //the remaining gamma^horizon weight goes to the discounted return after them,
+    //so the expected target is still the discounted return
+    fn mc_control_wtis(&mut self, ep:&Episode, c_info:&ControlInfo, off_policy:bool) {
+        let tt = ep.state.len();
+        let h = max(c_info.horizon, 1);
+        let gamma = c_info.gamma;
+        let reward = &ep.reward;
+        //discounted return and importance ratio from each step, filled backward
+        let mut g_v = vec![0.0; tt + 1];
+        let mut rho_v = vec![1.0; tt];
+        for k in (0..tt).rev() {
+            let s = &ep.state[k];
+            let a = &ep.action[k];
+            g_v[k] = reward[k] + gamma * g_v[k + 1];
+            let (mut x, mut y) = (0.0, 0.0);
+            let mut g_flat = 0.0;
+            let mut ratio = 1.0;//rho_(k+1:j-1)
             let mut gamma_h = 1.0;
-            for j in k..min(k + h, tt) {
-                let r = j as f64 * a_info.step_reward;
-                g_h += r;
-                let w_step = neg_gamma * gamma_h * w_h;
-                w += w_step;
-                g += w_step * g_h;
-                w_h *= p_vec[j];
-                gamma_h *= gamma_v;
+            let mut j = k;
+            while j < min(k + h, tt) {
+                g_flat += reward[j];
+                j += 1;
+                if j == tt {
+                    x += gamma_h * ratio * g_flat;
+                    y += gamma_h * ratio;
+                    break
+                }
+                x += (1.0 - gamma) * gamma_h * ratio * g_flat;
+                y += (1.0 - gamma) * gamma_h * ratio;
+                ratio *= rho_v[j];
+                gamma_h *= gamma;
             }
-            //TODO NaN value appears, check needed
-            q.w += w;
-            q.v += w * (g - q.v) / q.w;
-            let a_match = match self.improve_policy(s) {
-                Some(v) => v == a,
-                None => false,
-            };
-            match b {
-                Some(v) => {
-                    if !a_match { return }
-                    self.w *= 1.0 / v.p_epsilon(s, a, c_info);
-                },
-                None => {}
+            if j < tt {
+                //beyond the horizon, self.w is rho_(k+1:T-1)
+                x += gamma_h * self.w * (g_flat + g_v[j]);
+                y += gamma_h * self.w;
             }
-            gamma *= gamma_v;
+            let q = self.q_entry(s, a);
+            if y > 0.0 {
+                q.w += y;
+                q.v += (x - y * q.v) / q.w;
+            }
+            rho_v[k] = self.rho(ep, k, off_policy);
+            self.w *= rho_v[k];
         }
     }
 
     fn improve_policy(&mut self, s:&State) -> Option<&Action> {
-        let p = &mut *self.p_ref;
-        let a_map = match self.q.get(s) {
-            Some(v) => v,
-            None => return None,
-        };
-        let (a, _) = a_map.iter().max_by(|(_, q0), (_, q1)| q0.v.total_cmp(&q1.v)).unwrap();
-        p.state_action.insert(*s, *a);
-        Some(a)
-    }
-
-    fn p_epsilon(&self, s:&State, a:&Action, info:&ControlInfo) -> f64 {
-        let c_a = match self.q.get(s) {
-            Some(v) => v.len() as f64,
-            None => return 0.0,
-        };
-        match self.p_ref.state_action.get(s) {
-            Some(v) => {
-                let ep = info.epsilon;
-                if v == a { 1.0 - ep + ep / c_a }
-                else { ep / c_a }
-            },
-            None => return 0.0,
-        }
+        let (a, _) = self.q_state(s).max_by(|(_, q0), (_, q1)| q0.v.total_cmp(&q1.v))?;
+        self.p_ref.set(s, a);
+        self.p_ref.get(s)
     }
 
-    fn print_policy_sample(&self, f:&Field, info:&AgentInfo, msg:&str, p_start:Vec2) {
+    fn print_policy_sample(&self, f:&Field, t_info:&TrackInfo, msg:&str, p_start:Vec2) {
         println!("{}", msg);
-        let map = &self.p_ref.state_action;
+        let map = &*self.p_ref;
         let mut visit:HashSet<Vec2> = HashSet::new();
         visit.insert(p_start);
-        let mut agent = Agent::new(info);
-        agent.position = p_start;
-        let (finish, s) = loop {
-            let s = &agent.state();
-            let act = match map.get(s) {
+        //noiseless, ends on the first crash
+        let info = TrackInfo { p_vel_inc0:0.0, crash_reset:false, ..*t_info };
+        let mut env = Racetrack::new(f, &info, 0);
+        let mut s = env.reset_at(p_start);
+        let finish = loop {
+            let act = match map.get(&s) {
                 Some(v) => v,
                 None => {
                     println!("state not found {:?}", s);
-                    break (false, *s)
+                    break false
                 }
             };
-            agent.action(&act);
-            let p = &agent.position;
+            let (ss, _, done) = env.step(act);
+            let p = &ss.0;
+            if env.crash > 0 {
+                println!("position outside {:?} {:?}", s, p);
+                break false
+            }
             if visit.contains(p) {
                 println!("position visited (loop) {:?} {:?}", s, p);
-                break (false, *s)
+                break false
             }
             visit.insert(*p);
-            let (outside, finish) = f.is_outside(p);
-            if finish { break (true, *s) }
-            if outside {
-                println!("position outside {:?} {:?}", s, p);
-                break (false, *s)
-            }
+            if done { break true }
+            s = ss;
         };
         if !finish {
             println!("sample steps {}", visit.len());
-            match self.q.get(&s) {
-                Some(v) => {
-                    for (a, q) in v {
-                        println!("{:?} {:?}", a, q.v);
-                    }
-                },
-                None => {},
-            };
-            match map.get(&s) {
-                Some(v) => { println!("{:?}", v) },
-                None => {},
-            };
-            return
-        }
-        let empty = "   ";
-        for (i, (x_min, x_max)) in f.boundary.iter().enumerate().rev() {
-            for _ in 0..*x_min {
-                print!("{}", empty);
+            for (a, q) in self.q_state(&s) {
+                println!("{:?} {:?}", a, q.v);
             }
-            let x_max = *x_max;
-            for k in *x_min..=x_max {
-                if visit.contains(&(k, i as i32)) { print!("|+|") }
-                else { print!("| |"); }
+            if let Some(v) = map.get(&s) {
+                println!("{:?}", v)
             }
-            if x_max == f.finish_line {
-                for k in x_max+1..x_max+4 {
-                    if visit.contains(&(k, i as i32)) { print!(" + ") }
-                    else { print!("{}", empty); }
+            return
+        }
+        let (w, h) = f.cell.dimension;
+        for y in (0..h).rev() {
+            //a few extra columns for overshooting the finish
+            for x in 0..w + 3 {
+                let visited = visit.contains(&(x, y));
+                match f.get(&(x, y)) {
+                    Cell::Track | Cell::Start => print!("{}", if visited { "|+|" } else { "| |" }),
+                    _ => print!("{}", if visited { " + " } else { "   " }),
                 }
             }
             println!();
@@ -474,57 +520,662 @@ impl ActionValue {
 }
 
 impl Policy {
-    fn new() -> Self {
-        Self { state_action:BTreeMap::new() }
+    fn new(space:Space, corner:i32) -> Self {
+        Self { space, corner, state_action:vec![None; space.state_count()] }
+    }
+
+    fn get(&self, s:&State) -> Option<&Action> {
+        self.state_action[self.space.state(s)].as_ref()
+    }
+
+    fn set(&mut self, s:&State, a:Action) {
+        self.state_action[self.space.state(s)] = Some(a);
+    }
+
+    //epsilon-greedy, exploring never picks the action that stops the car
+    fn greedy(&self, s:&State) -> Action {
+        match self.get(s) {
+            Some(v) => *v,
+            None => {
+                let y = (s.0).1;
+                if y > self.corner { (1, 0) }
+                else { (0, 1) }
+            },
+        }
+    }
+
+    //the action that would stop the car, never explored
+    fn stop_action(&self, s:&State) -> Option<Action> {
+        let a = (-(s.1).0, -(s.1).1);
+        let a_max = self.space.a_min + self.space.a_range - 1;
+        let in_range = |x:i32| x >= self.space.a_min && x <= a_max;
+        if in_range(a.0) && in_range(a.1) { Some(a) } else { None }
+    }
+
+    //epsilon-greedy, exploring is uniform over the actions except stop_action
+    fn select_action(&self, s:&State, c_info:&ControlInfo, rng:&mut StdRng) -> Action {
+        let r:f64 = rng.gen();
+        if r < c_info.epsilon {
+            let stop = self.stop_action(s).map(|a| self.space.action(&a));
+            let n = self.space.action_count() - stop.map_or(0, |_| 1);
+            let mut aa = rng.gen_range(0..n);
+            if stop.is_some_and(|k| aa >= k) {
+                aa += 1;
+            }
+            return self.space.rev_action(aa)
+        }
+        self.greedy(s)
+    }
+
+    //b(a|s) of select_action, velocity noise is part of the track dynamics and does not change the chosen action
+    fn probability(&self, s:&State, a:&Action, c_info:&ControlInfo) -> f64 {
+        let stop = self.stop_action(s);
+        let n = (self.space.action_count() - stop.map_or(0, |_| 1)) as f64;
+        let ep = c_info.epsilon;
+        let p_explore = if stop == Some(*a) { 0.0 } else { ep / n };
+        if self.greedy(s) == *a { 1.0 - ep + p_explore } else { p_explore }
+    }
+}
+
+struct TrackFigure<'a> {
+    pub f:&'a Field,
+    pub trajectory:&'a [Vec<State>],
+    pub velocity_max:i32,//cells drawn around the field, a finishing move overshoots by up to this
+}
+
+impl Evaluation {
+    fn step_mean(&self) -> f64 {
+        if self.step.is_empty() { 0.0 }
+        else { self.step.iter().sum::<usize>() as f64 / self.step.len() as f64 }
+    }
+
+    fn print(&self, msg:&str, trajectory:bool) {
+        let mut step = self.step.clone();
+        step.sort_unstable();
+        let (mean, median) = (self.step_mean(), step.get(step.len() / 2).copied().unwrap_or(0));
+        println!("{} success {}/{} ({:.1}%) steps mean {:.1} median {} crash {}", msg, self.success, self.run,
+            100.0 * self.success as f64 / max(self.run, 1) as f64, mean, median, self.crash);
+        if trajectory {
+            for t in self.trajectory.iter() {
+                println!("{:?}", t.iter().map(|s| s.0).collect::<Vec<Vec2>>());
+            }
+        }
+    }
+}
+
+//greedy runs of pi from every start cell, unvisited states fall back to the default action
+fn evaluate(pi:&Policy, f:&Field, t_info:&TrackInfo, c_info:&ControlInfo, noise:bool) -> Evaluation {
+    let info = TrackInfo { p_vel_inc0:if noise { t_info.p_vel_inc0 } else { 0.0 }, ..*t_info };
+    let greedy = ControlInfo { epsilon:0.0, ..*c_info };
+    let run = if noise { c_info.eval_run } else { 1 };
+    let mut env = Racetrack::new(f, &info, c_info.seed);
+    let mut rng = StdRng::seed_from_u64(c_info.seed);
+    let mut e = Evaluation { run:0, success:0, crash:0, step:Vec::new(), trajectory:Vec::new() };
+    for k in 0..f.start.len() {
+        for r in 0..run {
+            let mut s = env.reset_at(f.indexed_start(k));
+            let mut trajectory = vec![s];
+            let mut finish = false;
+            for _ in 0..c_info.eval_step_max {
+                let act = pi.select_action(&s, &greedy, &mut rng);
+                let done;
+                (s, _, done) = env.step(&act);
+                trajectory.push(s);
+                if done {
+                    //without crash_reset a crash ends the run too
+                    finish = info.crash_reset || env.crash == 0;
+                    break
+                }
+            }
+            e.run += 1;
+            e.crash += env.crash;
+            if finish {
+                e.success += 1;
+                e.step.push(trajectory.len() - 1);
+            }
+            if r == 0 {
+                e.trajectory.push(trajectory);
+            }
+        }
+    }
+    e
+}
+
+impl<'a> TrackFigure<'a> {
+    const CELL:u32 = 24;//pixels
+
+    fn size(&self) -> (u32, u32) {
+        let (w, h) = self.f.cell.dimension;
+        let m = 2 * self.velocity_max;
+        ((w + m) as u32 * Self::CELL + 20, (h + m) as u32 * Self::CELL + 20)
+    }
+}
+
+//p0->p1 with the head drawn back from p1
+fn arrow(p0:(f64, f64), p1:(f64, f64)) -> Vec<(f64, f64)> {
+    let (dx, dy) = (p1.0 - p0.0, p1.1 - p0.1);
+    let l = (dx * dx + dy * dy).sqrt();
+    let head = 0.35f64.min(l * 0.5);
+    let (ux, uy) = (dx / l * head, dy / l * head);
+    let (c, s) = (0.9, 0.44);//cos, sin of the head angle
+    let h0 = (p1.0 - ux * c + uy * s, p1.1 - uy * c - ux * s);
+    let h1 = (p1.0 - ux * c - uy * s, p1.1 - uy * c + ux * s);
+    vec![p0, p1, h0, p1, h1]
+}
+
+impl<'a> Figure for TrackFigure<'a> {
+    fn draw<DB:DrawingBackend>(&self, _k:usize, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+        where DB::ErrorType: 'static {
+        let cell = &self.f.cell;
+        let m = self.velocity_max as f64;
+        let (w, h) = (cell.dimension.0 as f64, cell.dimension.1 as f64);
+        let mut chart = ChartBuilder::on(canvas)
+            .margin(10)
+            .build_cartesian_2d(-m..w + m, -m..h + m)?;
+        let rect = cell.iter().enumerate()
+            .filter(|(_, c)| **c != Cell::Wall)
+            .map(|(i, c)| {
+                let (x, y) = cell.rev_index(i);
+                let color = match c {
+                    Cell::Start => RGBColor(160, 220, 160),
+                    Cell::Finish => RGBColor(230, 160, 160),
+                    _ => WHITE,
+                };
+                ([(x as f64, y as f64), (x as f64 + 1.0, y as f64 + 1.0)], color)
+            });
+        chart.draw_series(rect.clone().map(|(r, color)| Rectangle::new(r, color.filled())))?;
+        chart.draw_series(rect.map(|(r, _)| Rectangle::new(r, BLACK.mix(0.3))))?;
+        let center = |p:&Vec2| (p.0 as f64 + 0.5, p.1 as f64 + 0.5);
+        //each step moves by the new velocity, except when a crash puts the car back to the start
+        let moved = |s:&[State]| {
+            let ((p0, _), (p1, v)) = (s[0], s[1]);
+            v != (0, 0) && (p1.0 - p0.0, p1.1 - p0.1) == v
+        };
+        for (k, t) in self.trajectory.iter().enumerate() {
+            let color = Palette99::pick(k).to_rgba();
+            chart.draw_series(t.first().map(|s| Circle::new(center(&s.0), 4, color.filled())))?;
+            chart.draw_series(t.windows(2).filter(|s| moved(s))
+                .map(|s| PathElement::new(arrow(center(&s[0].0), center(&s[1].0)), color.stroke_width(2))))?;
+        }
+        Ok(())
+    }
+}
+
+fn take<'a>(data:&mut &'a [u8], n:usize) -> Result<&'a [u8], Box<dyn Error>> {
+    if data.len() < n {
+        return Err("checkpoint is truncated".into())
+    }
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    Ok(head)
+}
+
+fn take_f64(data:&mut &[u8]) -> Result<f64, Box<dyn Error>> {
+    Ok(f64::from_le_bytes(take(data, 8)?.try_into()?))
+}
+
+const CHECKPOINT_TAG:&[u8] = b"RT5_12C2";
+const RUN_HEADER:[&str; 11] = ["seed", "thread", "batch", "algorithm", "estimator", "epsilon", "gamma", "horizon",
+    "step_max", "crash_clear", "episodes per check"];
+
+//settings the episodes and updates depend on, a resumed run must use the same ones
+fn run_header(c_info:&ControlInfo, thread:usize, interval:usize) -> [u64; 11] {
+    [c_info.seed, thread as u64, c_info.batch as u64, c_info.algorithm as u64, c_info.estimator as u64,
+        c_info.epsilon.to_bits(), c_info.gamma.to_bits(), c_info.horizon as u64, c_info.step_max as u64,
+        c_info.crash_clear as u64, interval as u64]
+}
+
+//tag, space header, run header, episode count, then b and pi,
+//the rngs need no saving since every check reseeds them from the episode count
+fn save_checkpoint(path:&str, ep_c:usize, header:&[u64], b:&Graph, pi:&Graph) -> Result<(), Box<dyn Error>> {
+    let mut out = CHECKPOINT_TAG.to_vec();
+    out.extend(b.p_ref.space.header().iter().flat_map(|v| v.to_le_bytes()));
+    out.extend(header.iter().flat_map(|v| v.to_le_bytes()));
+    out.extend((ep_c as u64).to_le_bytes());
+    b.save(&mut out);
+    pi.save(&mut out);
+    //a run stopped while writing keeps the previous checkpoint
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, out).map_err(|e| format!("failed to write checkpoint {}: {}", tmp, e))?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+//returns the episode count to continue from
+fn load_checkpoint(path:&str, header:&[u64], b:&mut Graph, pi:&mut Graph) -> Result<usize, Box<dyn Error>> {
+    let bytes = fs::read(path).map_err(|e| format!("failed to read checkpoint {}: {}", path, e))?;
+    let data = &mut &bytes[..];
+    if take(data, CHECKPOINT_TAG.len())? != CHECKPOINT_TAG {
+        return Err(format!("{} is not a racetrack checkpoint", path).into())
+    }
+    for v in b.p_ref.space.header() {
+        if i32::from_le_bytes(take(data, 4)?.try_into()?) != v {
+            return Err("checkpoint does not match the track or action space".into())
+        }
     }
+    for (v, name) in header.iter().zip(RUN_HEADER) {
+        if u64::from_le_bytes(take(data, 8)?.try_into()?) != *v {
+            return Err(format!("checkpoint was written with a different {}", name).into())
+        }
+    }
+    let ep_c = u64::from_le_bytes(take(data, 8)?.try_into()?) as usize;
+    b.load(data)?;
+    pi.load(data)?;
+    if !data.is_empty() {
+        return Err("checkpoint has trailing data".into())
+    }
+    Ok(ep_c)
 }
 
-fn iteration(c_info:&ControlInfo, a:&mut Agent, f:&Field, b:&mut Graph, pi:&mut Graph) {
-    let mut ep = Episode::new();
-    let mut ep_c = 0;
-    let a_info = a.info;
+//one batch from every worker, learned in worker order so the result does not depend on thread timing,
+//at most n episodes, the last workers get fewer or none
+fn train_batch(worker:&mut [Worker], i0:usize, n:usize, c_info:&ControlInfo, b:&mut Graph, pi:&mut Graph, log:&mut RunLog) -> usize {
+    //every worker rolls out with the same snapshot of b
+    let b_p:&Policy = b.p_ref;
+    if let [w] = worker {
+        w.generate(i0, n, b_p, c_info);
+    }
+    else {
+        thread::scope(|sc| {
+            for (k, w) in worker.iter_mut().enumerate() {
+                let i = k * w.ep.len();
+                let n = n.saturating_sub(i);
+                w.n = 0;
+                if n > 0 {
+                    sc.spawn(move || w.generate(i0 + i, n, b_p, c_info));
+                }
+            }
+        });
+    }
+    //b learns its own values with weighted IS whatever the estimator of pi
+    let b_info = ControlInfo { estimator:Estimator::Weighted, ..*c_info };
+    let mut n = 0;
+    for ep in worker.iter().flat_map(|w| w.ep[..w.n].iter()) {
+        match c_info.algorithm {
+            Algorithm::OffPolicy => {
+                b.mc_control(ep, &b_info, false);
+                pi.mc_control(ep, c_info, true);
+            },
+            Algorithm::OnPolicyFirstVisit | Algorithm::ExploringStarts => b.mc_control_on(ep, c_info, true),
+            Algorithm::OnPolicyEveryVisit => b.mc_control_on(ep, c_info, false),
+        }
+        log.push(ep.length, ep.ret);
+        n += 1;
+    }
+    n
+}
+
+fn iteration(c_info:&ControlInfo, env:&mut Racetrack, b:&mut Graph, pi:&mut Graph) -> Result<(), Box<dyn Error>> {
+    let thread = match c_info.thread {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let batch = max(c_info.batch, 1);
+    //b is the learned policy itself when on-policy
+    let off_policy = c_info.algorithm == Algorithm::OffPolicy;
+    let interval = max(c_info.max_episode / c_info.episode_check, 1);
+    let header = run_header(c_info, thread, interval);
+    let mut ep_c = match (c_info.checkpoint, c_info.resume) {
+        (Some(path), true) => load_checkpoint(path, &header, b, pi)?,
+        _ => 0,
+    };
+    let ep_0 = ep_c;
+    let f = env.field;
     let now = Instant::now();
-    let interval = c_info.max_episode / c_info.episode_check;
     while ep_c < c_info.max_episode {
+        //the last check stops at max_episode
+        let ep_n = min(interval, c_info.max_episode - ep_c);
+        //reseeded every check, so a resumed run continues with the same numbers
+        let mut rng = StdRng::seed_from_u64(c_info.seed.wrapping_add(ep_c as u64));
+        let mut worker:Vec<Worker> = (0..thread).map(|_| Worker::new(env.field, env.info, rng.gen(), batch)).collect();
         let mut ep_cc = 0;
-        while ep_cc < interval {
-            ep_cc += 1;
-            ep.generate(ep_c + ep_cc, b.p_ref, f, a, c_info);
-            b.mc_control_wis(&ep, a.info, c_info, None);
-            pi.mc_control(&ep, a.info, c_info, Some(b));
-        }
-        let elapsed = now.elapsed().as_secs();
-        println!("elapsed:{}", elapsed);
-        let sample_start = f.random_start(&mut ep.rng);
-        b.print_policy_sample(f, a_info, "b:", sample_start);
-        pi.print_policy_sample(f, a_info, "pi:", sample_start);
+        let mut log = RunLog::new();
+        while ep_cc < ep_n {
+            ep_cc += train_batch(&mut worker, ep_c + ep_cc, ep_n - ep_cc, c_info, b, pi, &mut log);
+        }
+        let elapsed = now.elapsed().as_secs_f64();
+        let n = ep_cc as f64;
+        println!("elapsed:{:.0} episodes/s:{:.0} length mean:{:.1} return mean:{:.1}", elapsed, (ep_c + ep_cc - ep_0) as f64 / elapsed,
+            log.length.iter().sum::<f64>() / n, log.ret.iter().sum::<f64>() / n);
+        let (sample_start, _) = env.reset();
+        b.print_policy_sample(f, env.info, "b:", sample_start);
+        let target = if off_policy {
+            pi.print_policy_sample(f, env.info, "pi:", sample_start);
+            &*pi.p_ref
+        }
+        else { &*b.p_ref };
+        let e = evaluate(target, f, env.info, c_info, false);
+        e.print("greedy:", true);
+        evaluate(target, f, env.info, c_info, true).print("greedy with noise:", false);
+        if let Some(backend) = &c_info.figure {
+            let name = if c_info.figure_per_check { format!("5_12_f{}_{:04}", c_info.field, ep_c / interval) }
+                else { format!("5_12_f{}", c_info.field) };
+            let fig = TrackFigure { f, trajectory:&e.trajectory, velocity_max:env.info.velocity_max };
+            backend.render(&name, fig.size(), 0, &fig)?;
+        }
         ep_c += ep_cc;
+        if let Some(path) = c_info.checkpoint {
+            save_checkpoint(path, ep_c, &header, b, pi)?;
+        }
     }
+    Ok(())
 }
 
-pub fn run() -> Result<(), Box<dyn Error>> {
-    let c_info = ControlInfo {
-        max_episode:100000000, episode_check:20,
-        epsilon:0.55, gamma:0.2, horizon:4,
-        estimator:1, field:1,
-    };
+//episodes per second of the off-policy learner on one thread, the same episodes on every run
+fn benchmark(c_info:&ControlInfo, a_info:&AgentInfo, t_info:&TrackInfo, f:&Field, episodes:usize) {
+    let c_info = ControlInfo { algorithm:Algorithm::OffPolicy, ..*c_info };
+    let space = Space::new(f, t_info, a_info);
+    let mut b = Policy::new(space, f.corner);
+    let mut pi = Policy::new(space, f.corner);
+    let mut g_b = Graph::new(&mut b);
+    let mut g_pi = Graph::new(&mut pi);
+    let mut worker = vec![Worker::new(f, t_info, c_info.seed, max(c_info.batch, 1))];
+    let mut log = RunLog::new();
+    let now = Instant::now();
+    let mut i = 0;
+    while i < episodes {
+        i += train_batch(&mut worker, i, episodes - i, &c_info, &mut g_b, &mut g_pi, &mut log);
+    }
+    let elapsed = now.elapsed().as_secs_f64();
+    let steps = log.length.iter().sum::<f64>();
+    println!("episodes:{} steps:{} seconds:{:.2} episodes/s:{:.0} steps/s:{:.0}", i, steps, elapsed,
+        i as f64 / elapsed, steps / elapsed);
+}
+
+//spread of the learned start state value over independent runs, for each estimator
+fn compare_estimator(c_info:&ControlInfo, a_info:&AgentInfo, env:&mut Racetrack, runs:usize, episodes:usize) {
+    let s0 = (env.field.indexed_start(0), (0, 0));
+    let space = Space::new(env.field, env.info, a_info);
+    for estimator in [Estimator::Ordinary, Estimator::Weighted, Estimator::PerDecision,
+        Estimator::DiscountingAware, Estimator::WeightedTruncated] {
+        let c_info = ControlInfo { estimator, ..*c_info };
+        let b_info = ControlInfo { estimator:Estimator::Weighted, ..c_info };
+        let mut v:Vec<f64> = Vec::new();
+        for run in 0..runs {
+            let mut b = Policy::new(space, env.field.corner);
+            let mut pi = Policy::new(space, env.field.corner);
+            let mut g_b = Graph::new(&mut b);
+            let mut g_pi = Graph::new(&mut pi);
+            let mut ep = Episode::new();
+            let mut rng = StdRng::seed_from_u64(c_info.seed + run as u64);
+            for i in 0..episodes {
+                ep.generate(i, g_b.p_ref, env, &c_info, &mut rng);
+                g_b.mc_control(&ep, &b_info, false);
+                g_pi.mc_control(&ep, &c_info, true);
+            }
+            let q = match g_pi.p_ref.get(&s0) {
+                Some(a) => g_pi.q_state(&s0).find(|(aa, _)| aa == a).map_or(0.0, |(_, q)| q.v),
+                None => 0.0,
+            };
+            v.push(q);
+        }
+        let n = v.len() as f64;
+        let mean = v.iter().sum::<f64>() / n;
+        let var = v.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / n;
+        println!("{:?}: mean {:.3} std {:.3}", estimator, mean, var.sqrt());
+    }
+}
+
+//sample efficiency of each algorithm on both built-in tracks, greedy evaluation with noise after training
+fn compare_algorithm(c_info:&ControlInfo, a_info:&AgentInfo, t_info:&TrackInfo, runs:usize, episodes:usize) -> Result<(), Box<dyn Error>> {
+    for field in [1, 2] {
+        let f = setup_field(&ControlInfo { field, ..*c_info })?;
+        let space = Space::new(&f, t_info, a_info);
+        for algorithm in [Algorithm::OffPolicy, Algorithm::OnPolicyFirstVisit, Algorithm::OnPolicyEveryVisit,
+            Algorithm::ExploringStarts] {
+            let (mut success, mut step) = (0.0, 0.0);
+            for run in 0..runs {
+                let c_info = ControlInfo { field, algorithm, seed:c_info.seed + run as u64, ..*c_info };
+                let mut b = Policy::new(space, f.corner);
+                let mut pi = Policy::new(space, f.corner);
+                let mut g_b = Graph::new(&mut b);
+                let mut g_pi = Graph::new(&mut pi);
+                let mut worker = vec![Worker::new(&f, t_info, c_info.seed, max(c_info.batch, 1))];
+                let mut i = 0;
+                while i < episodes {
+                    i += train_batch(&mut worker, i, episodes - i, &c_info, &mut g_b, &mut g_pi, &mut RunLog::new());
+                }
+                let target = if algorithm == Algorithm::OffPolicy { &*g_pi.p_ref } else { &*g_b.p_ref };
+                let e = evaluate(target, &f, t_info, &c_info, true);
+                success += e.success as f64 / e.run as f64;
+                step += e.step_mean();
+            }
+            let n = runs as f64;
+            println!("field {} {:?}: success {:.1}% steps mean {:.1}", field, algorithm, 100.0 * success / n, step / n);
+        }
+    }
+    Ok(())
+}
+
+//behaviour episode length and return of each algorithm over independent seeds, to csv and figure
+fn learning_curve(c_info:&ControlInfo, a_info:&AgentInfo, t_info:&TrackInfo, f:&Field, runs:usize, episodes:usize) -> Result<(), Box<dyn Error>> {
+    let space = Space::new(f, t_info, a_info);
+    let mut curve:Vec<LearningCurve> = Vec::new();
+    for algorithm in [Algorithm::OffPolicy, Algorithm::OnPolicyFirstVisit, Algorithm::OnPolicyEveryVisit,
+        Algorithm::ExploringStarts] {
+        let log:Vec<RunLog> = (0..runs).map(|run| {
+            let c_info = ControlInfo { algorithm, seed:c_info.seed + run as u64, ..*c_info };
+            let mut b = Policy::new(space, f.corner);
+            let mut pi = Policy::new(space, f.corner);
+            let mut g_b = Graph::new(&mut b);
+            let mut g_pi = Graph::new(&mut pi);
+            let mut worker = vec![Worker::new(f, t_info, c_info.seed, max(c_info.batch, 1))];
+            let mut log = RunLog::new();
+            while log.length.len() < episodes {
+                let i = log.length.len();
+                train_batch(&mut worker, i, episodes - i, &c_info, &mut g_b, &mut g_pi, &mut log);
+            }
+            log
+        }).collect();
+        curve.push(LearningCurve::new(&format!("{:?}", algorithm), &log));
+    }
+    let name = format!("5_12_f{}_curve", c_info.field);
+    write_csv(&format!("{}.csv", name), &curve)?;
+    if let Some(backend) = &c_info.figure {
+        backend.render(&name, (2160, 720), 0, &LearningFigure { curve:&curve })?;
+    }
+    Ok(())
+}
+
+fn setup_field(c_info:&ControlInfo) -> Result<Field, Box<dyn Error>> {
     let mut f = Field::new();
     match c_info.field {
+        0 => f = Field::load(c_info.track_file)?,
         1 => f.setup_v1(),
         2 => f.setup_v2(),
         _ => { return Err(format!("invalid field setup {}", c_info.field).into()) }
     }
+    Ok(f)
+}
+
+pub fn run() -> Result<(), Box<dyn Error>> {
+    let c_info = ControlInfo {
+        max_episode:100000000, episode_check:20,
+        epsilon:0.55, gamma:0.2, horizon:4,
+        algorithm:Algorithm::OffPolicy,
+        estimator:Estimator::WeightedTruncated, field:1, track_file:"track/v1.txt",
+        seed:0, thread:4, batch:100, step_max:10000, crash_clear:false,
+        eval_run:20, eval_step_max:200,
+        figure:None,// Some(Backend::Png),
+        figure_per_check:false,
+        checkpoint:None,// Some("5_12.ckpt"),
+        resume:false,
+    };
+    let f = setup_field(&c_info)?;
     f.print();
-    let mut a_info = AgentInfo {
-        velocity_max:5, action:(-1, 1), step_reward:-1.0,
-        p_vel_inc0:0.1, a_space:(0, 0.0),
+    let t_info = TrackInfo {
+        velocity_max:5, step_reward:-1.0, crash_reward:-5.0,
+        crash_reset:true, p_vel_inc0:0.1,
     };
-    a_info.setup();
-    let mut agent = Agent::new(&a_info);
-    let mut b = Policy::new();
-    let mut pi = Policy::new();
+    let a_info = AgentInfo { action:(-1, 1) };
+    let mut env = Racetrack::new(&f, &t_info, c_info.seed);
+    //estimator comparison switch: (runs, episodes)
+    let compare:Option<(usize, usize)> =
+        None;
+        // Some((10, 10000));
+    if let Some((runs, episodes)) = compare {
+        compare_estimator(&c_info, &a_info, &mut env, runs, episodes);
+        return Ok(())
+    }
+    //algorithm comparison switch: (runs, episodes)
+    let compare_algo:Option<(usize, usize)> =
+        None;
+        // Some((5, 20000));
+    if let Some((runs, episodes)) = compare_algo {
+        let c_info = ControlInfo { epsilon:0.1, ..c_info };
+        return compare_algorithm(&c_info, &a_info, &t_info, runs, episodes)
+    }
+    //learning curve switch: (runs, episodes), same settings as the algorithm comparison
+    let curve:Option<(usize, usize)> =
+        None;
+        // Some((10, 20000));
+    if let Some((runs, episodes)) = curve {
+        let c_info = ControlInfo { epsilon:0.1, ..c_info };
+        return learning_curve(&c_info, &a_info, &t_info, &f, runs, episodes)
+    }
+    //timing switch: episodes from c_info.seed on one thread, run with --release
+    //field 1, 400000 episodes, gamma 0.9: BTreeMap tables about 25000 episodes/s, dense tables about 48000
+    let bench:Option<usize> =
+        None;
+        // Some(400000);
+    if let Some(episodes) = bench {
+        benchmark(&c_info, &a_info, &t_info, &f, episodes);
+        return Ok(())
+    }
+    let space = Space::new(&f, &t_info, &a_info);
+    let mut b = Policy::new(space, f.corner);
+    let mut pi = Policy::new(space, f.corner);
     let mut g_b = Graph::new(&mut b);
     let mut g_pi = Graph::new(&mut pi);
-    iteration(&c_info, &mut agent, &f, &mut g_b, &mut g_pi);
-    Ok(())
-}
\ No newline at end of file
+    iteration(&c_info, &mut env, &mut g_b, &mut g_pi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //one state, L:+1 then back with probability P, R:0 and done, b picks each with 1/2, pi is greedy (L)
+    const S0:State = ((0, 0), (0, 0));
+    const L:Action = (0, 1);
+    const R:Action = (1, 1);
+    const P:f64 = 0.3;
+    const GAMMA:f64 = 0.9;
+
+    fn space() -> Space {
+        Space { size:(1, 1), velocity_max:0, a_min:0, a_range:2 }
+    }
+
+    fn control(estimator:Estimator) -> ControlInfo {
+        ControlInfo {
+            max_episode:0, episode_check:1, epsilon:0.0, gamma:GAMMA, horizon:2,
+            algorithm:Algorithm::OffPolicy, estimator, field:0, track_file:"",
+            seed:0, thread:1, batch:1, step_max:0, crash_clear:false, eval_run:0, eval_step_max:0,
+            figure:None, figure_per_check:false, checkpoint:None, resume:false,
+        }
+    }
+
+    fn episode(ep:&mut Episode, rng:&mut StdRng) {
+        *ep = Episode::new();
+        loop {
+            let left = rng.gen::<f64>() < 0.5;
+            ep.state.push(S0);
+            ep.action.push(if left { L } else { R });
+            ep.reward.push(if left { 1.0 } else { 0.0 });
+            ep.prob.push(0.5);
+            if !left || rng.gen::<f64>() >= P { break }
+        }
+    }
+
+    //q(S0, L) learned by pi from episodes of b
+    fn estimate(estimator:Estimator, seed:u64, episodes:usize) -> f64 {
+        let c_info = control(estimator);
+        let mut pi = Policy::new(space(), 0);
+        let mut g = Graph::new(&mut pi);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut ep = Episode::new();
+        for _ in 0..episodes {
+            episode(&mut ep, &mut rng);
+            g.mc_control(&ep, &c_info, true);
+        }
+        let q = g.q_state(&S0).find(|(a, _)| *a == L).map_or(0.0, |(_, q)| q.v);
+        q
+    }
+
+    fn spread(v:&[f64]) -> (f64, f64) {
+        let n = v.len() as f64;
+        let mean = v.iter().sum::<f64>() / n;
+        (mean, v.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / n)
+    }
+
+    #[test]
+    fn estimators_converge() {
+        let exact = 1.0 / (1.0 - GAMMA * P);
+        for estimator in [Estimator::Ordinary, Estimator::Weighted, Estimator::PerDecision,
+            Estimator::DiscountingAware, Estimator::WeightedTruncated] {
+            let v:Vec<f64> = (0..20).map(|seed| estimate(estimator, seed, 5000)).collect();
+            assert!(v.iter().all(|x| x.is_finite()), "{:?} {:?}", estimator, v);
+            let (mean, _) = spread(&v);
+            assert!((mean - exact).abs() < 0.05, "{:?} {} against {}", estimator, mean, exact);
+        }
+    }
+
+    #[test]
+    fn truncated_variance_bounded() {
+        let var = |estimator, episodes| {
+            let v:Vec<f64> = (0..40).map(|seed| estimate(estimator, seed, episodes)).collect();
+            assert!(v.iter().all(|x| x.is_finite()));
+            spread(&v).1
+        };
+        let (short, long) = (var(Estimator::WeightedTruncated, 500), var(Estimator::WeightedTruncated, 4000));
+        //shrinks with more episodes, and stays below ordinary IS
+        assert!(long < short / 2.0, "{} {}", short, long);
+        assert!(long < var(Estimator::Ordinary, 4000), "{}", long);
+        assert!(long < 1.5 * var(Estimator::Weighted, 4000), "{}", long);
+    }
+
+    //b and pi with their weights after iteration
+    fn train(c_info:&ControlInfo, f:&Field, t_info:&TrackInfo) -> Result<Vec<u8>, Box<dyn Error>> {
+        let space = Space::new(f, t_info, &AgentInfo { action:(-1, 1) });
+        let mut b = Policy::new(space, f.corner);
+        let mut pi = Policy::new(space, f.corner);
+        let mut g_b = Graph::new(&mut b);
+        let mut g_pi = Graph::new(&mut pi);
+        let mut env = Racetrack::new(f, t_info, c_info.seed);
+        iteration(c_info, &mut env, &mut g_b, &mut g_pi)?;
+        let mut out = Vec::new();
+        g_b.save(&mut out);
+        g_pi.save(&mut out);
+        Ok(out)
+    }
+
+    #[test]
+    fn resume_matches_straight_run() {
+        let mut f = Field::new();
+        f.setup_v1();
+        let t_info = TrackInfo { velocity_max:5, step_reward:-1.0, crash_reward:-5.0, crash_reset:true, p_vel_inc0:0.1 };
+        let path = std::env::temp_dir().join(format!("rl_exercise_5_12_{}.ckpt", std::process::id()));
+        let path:&'static str = Box::leak(path.to_string_lossy().into_owned().into_boxed_str());
+        let c_info = ControlInfo {
+            max_episode:1200, episode_check:2, epsilon:0.3, gamma:0.9,
+            field:1, thread:2, batch:50, step_max:1000, eval_run:1, eval_step_max:50,
+            ..control(Estimator::WeightedTruncated)
+        };
+        let straight = train(&c_info, &f, &t_info).unwrap();
+        //first half and a checkpoint, then the second half from it
+        let half = ControlInfo { max_episode:600, episode_check:1, checkpoint:Some(path), ..c_info };
+        let half_state = train(&half, &f, &t_info).unwrap();
+        assert!(half_state != straight);
+        let resume = ControlInfo { checkpoint:Some(path), resume:true, ..c_info };
+        //other settings are refused and leave the checkpoint as it is
+        for c in [ControlInfo { seed:1, ..resume }, ControlInfo { thread:3, ..resume }, ControlInfo { batch:20, ..resume },
+            ControlInfo { epsilon:0.2, ..resume }, ControlInfo { estimator:Estimator::Weighted, ..resume },
+            ControlInfo { algorithm:Algorithm::OnPolicyFirstVisit, ..resume }, ControlInfo { episode_check:3, ..resume }] {
+            assert!(train(&c, &f, &t_info).is_err());
+        }
+        let resumed = train(&resume, &f, &t_info).unwrap();
+        assert!(straight == resumed, "resumed run differs from the straight one");
+        //resuming reads the file
+        std::fs::remove_file(path).unwrap();
+        assert!(train(&resume, &f, &t_info).is_err());
+    }
+}
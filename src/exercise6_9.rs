@@ -1,62 +1,321 @@
 use std::cmp::{ min, max };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::time::Instant;
+use std::f64::consts::PI;
+use std::fs;
+use std::mem;
 use rand::prelude::*;
+use rand::rngs::StdRng;
+use plotters::{prelude::*, coord::Shift};
 
+use crate::curve::{ RunLog, LearningCurve, LearningFigure, write_csv };
+use crate::figure::{ Backend, Figure };
 use crate::nd_vec::NdVec2;
 
 type Vec2 = (i32, i32);
 type State = Vec2;
 type Action = Vec2;
-// type StateAction = (State, Action);
+type SweepLine = (usize, Vec<(f64, f64)>);//n, (alpha, mean steps)
+type StateAction = (State, Action);
+type Outcome = (State, f64, bool, usize);//(next state, reward, terminal, count)
 // type EpisodeStep = (State, Action, i32);
 
+#[derive(Clone)]
 struct World {
     pub size:(usize, usize),
     pub start:Vec2,
-    pub goal:Vec2,
-    pub wind:Vec<i32>,
+    pub goal:HashSet<Vec2>,
+    pub hazard:HashSet<Vec2>,//back to start, like the cliff
+    pub reward:HashMap<Vec2, f64>,//of entering the cell, instead of the step reward
+    pub wind:HashMap<Vec2, Vec2>,//pushes every move out of the cell
+    pub block:HashSet<Vec2>,//obstacles, moves into them stay put
+    pub change:Option<(usize, HashSet<Vec2>)>,//(time step, obstacles from then on)
+    pub time:usize,//steps taken by all episodes so far
 }
 
+//kinds of map cells
+#[derive(Clone, Copy)]
+enum MapCell {
+    Free,
+    Wall,
+    Start,
+    Goal(Option<f64>),//None:step reward
+    Hazard(f64),
+    Wind(Vec2),
+}
+
+//changing mazes of Sutton & Barto 8.3
+#[derive(Clone, Copy, Debug)]
+enum Maze {
+    Blocking,
+    Shortcut,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Algorithm {
+    Sarsa,
+    QLearning,
+    ExpectedSarsa,
+    NStepSarsa,
+    NStepOffPolicySarsa,//epsilon-greedy behaviour, greedy target
+    TreeBackup,//greedy target
+    SarsaLambda,
+    TrueOnlineSarsa,//always dutch traces
+    DynaQ,
+    DynaQPlus,//exploration bonus kappa * sqrt(steps since the last real visit)
+    DoubleQLearning,
+    DoubleExpectedSarsa,//epsilon-greedy on the updated table, valued by the other
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Trace {
+    Accumulating,
+    Replacing,
+    Dutch,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ModelKind {
+    Deterministic,//last outcome seen
+    Sampled,//outcomes seen, drawn by count
+}
+
+#[derive(Clone, Copy)]
 struct ControlInfo {
     pub max_episode:usize,
     pub episode_check:usize,
     pub epsilon:f64,
     pub alpha:f64,
+    pub gamma:f64,
+    pub n:usize,//steps of the n-step algorithms
+    pub lambda:f64,
+    pub trace:Trace,
+    pub trace_min:f64,//traces decayed below this are dropped
+    pub planning:usize,//Dyna updates per real step
+    pub kappa:f64,
+    pub model:ModelKind,
     pub stochastic_wind:bool,
+    pub map:Option<&'static str>,//None:the windy gridworld
+    pub algorithm:Algorithm,
+    pub curve_run:usize,//learning curves averaged over runs, 0:no curves
+    pub seed:u64,//run k of the curves uses seed + k
+    pub figure:Backend,
 }
 
 struct AgentInfo {
     pub action:NdVec2<i32>,
     pub no_stay:bool,
     pub step_reward:f64,
+    pub limit:HashMap<State, usize>,//states allowed only the first k action indices
 }
 
 struct Agent<'a> {
     pub info:&'a AgentInfo,
     pub position:Vec2,
-    pub rng:ThreadRng,
+    pub ret:f64,//rewards since reset
+    pub rng:StdRng,
 }
 
 struct Graph<'a> {
     pub q:NdVec2<NdVec2<f64>>,//<state:<Action: ActionValue>>
+    pub q2:NdVec2<NdVec2<f64>>,//second table of the double algorithms, 0 otherwise
+    pub e:NdVec2<NdVec2<f64>>,//eligibility traces, same layout as q
+    pub model:Model,
     pub p_ref:&'a mut Policy,
+    pub a_info:&'a AgentInfo,
+}
+
+//learned from real steps for the Dyna planning updates
+struct Model {
+    pub transition:HashMap<StateAction, (Vec<Outcome>, usize)>,//(outcomes, time of the last real visit)
+    pub key:Vec<StateAction>,//in order of the first visit
+    pub state:Vec<State>,
+    pub time:usize,//real steps so far
 }
 
 struct Policy {
     p:NdVec2<Action>,
 }
 
+//finished episodes over time steps, the cumulative reward with 1 per goal, the maze changes at the marker
+struct MazeFigure {
+    pub maze:Maze,
+    pub change:usize,
+    pub curve:Vec<(String, Vec<f64>)>,
+}
+
+//share of left actions in A per episode, optimal is epsilon / 2
+struct BiasFigure {
+    pub optimal:f64,
+    pub curve:Vec<(String, Vec<f64>)>,
+}
+
+//Sutton & Barto figure 6.5 on a row: B (0, 0) <- A (1, 0) -> terminal (2, 0),
+//every action of B ends with a N(mean, sd) reward, all other rewards are 0
+struct BiasMdp {
+    pub b_action:usize,
+    pub mean:f64,
+    pub sd:f64,
+}
+
+//mean steps per episode over alpha, a line for each n, a panel for each algorithm
+struct NAlphaFigure {
+    pub sweep:Vec<(Algorithm, Vec<SweepLine>)>,
+}
+
 impl World {
+    //Sutton & Barto example 6.5, wind up by column, none in the goal as no move leaves it
+    fn windy() -> Self {
+        let column = [0, 0, 0, 1, 1, 1, 2, 2, 1, 0];
+        let goal = (7, 3);
+        let wind = (0..7)
+            .flat_map(|y| column.iter().enumerate().filter(|(_, v)| **v != 0).map(move |(x, v)| ((x as i32, y), (0, *v))))
+            .filter(|(p, _)| *p != goal)
+            .collect();
+        World {
+            size:(10, 7), start:(0, 3), goal:HashSet::from([goal]),
+            hazard:HashSet::new(), reward:HashMap::new(), wind,
+            block:HashSet::new(), change:None, time:0,
+        }
+    }
+
+    //'.':free, '#':wall, 'S':start, 'G':goal, 'H':hazard at -100, '1'-'9':wind up,
+    //first line is the top row, short rows end in walls, after an empty line more cells can be defined,
+    //one per line: "<c> goal [reward]", "<c> hazard <reward>", "<c> wind <dx> <dy>" or "<c> wall"
+    fn load(path:&str) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read map {}: {}", path, e))?;
+        World::parse(&text)
+    }
+
+    //the text of a map file
+    fn parse(text:&str) -> Result<Self, Box<dyn Error>> {
+        let mut lines = text.trim_start_matches(['\r', '\n']).trim_end().lines();
+        let grid:Vec<&str> = lines.by_ref().take_while(|l| !l.is_empty()).collect();
+        let mut legend:HashMap<char, MapCell> = HashMap::from([
+            ('.', MapCell::Free), ('#', MapCell::Wall), ('S', MapCell::Start),
+            ('G', MapCell::Goal(None)), ('H', MapCell::Hazard(-100.0)),
+        ]);
+        for d in 1..=9 {
+            legend.insert(char::from_digit(d, 10).unwrap(), MapCell::Wind((0, d as i32)));
+        }
+        for (i, line) in lines.enumerate() {
+            let at = || format!("map legend at line {}", grid.len() + 2 + i);
+            let token:Vec<&str> = line.split_whitespace().collect();
+            if token.is_empty() { continue }
+            let mut c = token[0].chars();
+            let (Some(key), None) = (c.next(), c.next()) else {
+                return Err(format!("{}: '{}' is not a single character", at(), token[0]).into())
+            };
+            let number = |k:usize| -> Result<f64, Box<dyn Error>> {
+                let v = token.get(k).ok_or_else(|| format!("{}: missing value", at()))?;
+                Ok(v.parse::<f64>().map_err(|e| format!("{}: {}", at(), e))?)
+            };
+            let cell = match token.get(1).copied() {
+                Some("goal") => MapCell::Goal(if token.len() > 2 { Some(number(2)?) } else { None }),
+                Some("hazard") => MapCell::Hazard(number(2)?),
+                Some("wind") => MapCell::Wind((number(2)? as i32, number(3)? as i32)),
+                Some("wall") => MapCell::Wall,
+                v => return Err(format!("{}: invalid cell kind {:?}", at(), v).into()),
+            };
+            legend.insert(key, cell);
+        }
+        let width = grid.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+        let mut w = World {
+            size:(width, grid.len()), start:(0, 0), goal:HashSet::new(),
+            hazard:HashSet::new(), reward:HashMap::new(), wind:HashMap::new(),
+            block:HashSet::new(), change:None, time:0,
+        };
+        let mut start = Vec::new();
+        for (y, line) in grid.iter().rev().enumerate() {
+            let n = line.chars().count();
+            for (x, c) in line.chars().chain(std::iter::repeat_n('#', width - n)).enumerate() {
+                let p = (x as i32, y as i32);
+                match legend.get(&c) {
+                    Some(MapCell::Free) => {},
+                    Some(MapCell::Wall) => { w.block.insert(p); },
+                    Some(MapCell::Start) => start.push(p),
+                    Some(MapCell::Goal(r)) => {
+                        w.goal.insert(p);
+                        if let Some(r) = r { w.reward.insert(p, *r); }
+                    },
+                    Some(MapCell::Hazard(r)) => {
+                        w.hazard.insert(p);
+                        w.reward.insert(p, *r);
+                    },
+                    Some(MapCell::Wind(v)) => { w.wind.insert(p, *v); },
+                    None => return Err(format!("invalid map cell '{}' at line {} column {}", c, grid.len() - y, x + 1).into()),
+                }
+            }
+        }
+        if start.len() != 1 {
+            return Err(format!("map needs one start cell, found {}", start.len()).into())
+        }
+        w.start = start[0];
+        w.validate()?;
+        Ok(w)
+    }
+
+    //some goal must be reachable from the start by plain moves around walls and hazards
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.goal.is_empty() {
+            return Err("map has no goal cell".into())
+        }
+        let (w, h) = (self.size.0 as i32, self.size.1 as i32);
+        let mut visit:HashSet<Vec2> = HashSet::from([self.start]);
+        let mut open:VecDeque<Vec2> = VecDeque::from([self.start]);
+        while let Some(p) = open.pop_front() {
+            if self.is_terminal(&p) { return Ok(()) }
+            for d in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                let pp = (p.0 + d.0, p.1 + d.1);
+                if pp.0 < 0 || pp.1 < 0 || pp.0 >= w || pp.1 >= h { continue }
+                if self.block.contains(&pp) || self.hazard.contains(&pp) { continue }
+                if visit.insert(pp) { open.push_back(pp) }
+            }
+        }
+        Err("goal is unreachable from start".into())
+    }
+
     fn is_terminal(&self, p:&Vec2) -> bool {
-        self.goal == *p
+        self.goal.contains(p)
+    }
+
+    //one step of any episode, swaps the obstacles when their time has come
+    fn tick(&mut self) {
+        self.time += 1;
+        if matches!(&self.change, Some((t, _)) if *t <= self.time) {
+            let (_, block) = self.change.take().unwrap();
+            self.block = block;
+        }
+    }
+}
+
+impl Maze {
+    //(world, time steps to run), the walls of the changed map take over mid-run
+    fn world(&self) -> Result<(World, usize), Box<dyn Error>> {
+        let (map, changed, time, steps) = match self {
+            Maze::Blocking => ("map/blocking.txt", "map/blocking_changed.txt", 1000, 3000),
+            Maze::Shortcut => ("map/shortcut.txt", "map/shortcut_changed.txt", 3000, 6000),
+        };
+        let mut w = World::load(map)?;
+        w.change = Some((time, World::load(changed)?.block));
+        Ok((w, steps))
+    }
+}
+
+impl AgentInfo {
+    //indices of the action grid allowed in s, without (0, 0) when no_stay
+    fn actions(&self, s:&State) -> impl Iterator<Item = usize> + '_ {
+        let stay = self.action.index(&(0, 0));
+        let (w, h) = self.action.dimension;
+        let count = self.limit.get(s).copied().unwrap_or((w * h) as usize);
+        (0..count).filter(move |k| !self.no_stay || *k != stay)
     }
 }
 
 impl<'a> Agent<'a> {
-    fn new(info:&'a AgentInfo) -> Self {
-        Self { info, position:(0, 0), rng:rand::thread_rng() }
+    fn new(info:&'a AgentInfo, seed:u64) -> Self {
+        Self { info, position:(0, 0), ret:0.0, rng:StdRng::seed_from_u64(seed) }
     }
 
     fn state(&self) -> State {
@@ -65,6 +324,7 @@ impl<'a> Agent<'a> {
 
     fn reset(&mut self, p:&Vec2) {
         self.position = *p;
+        self.ret = 0.0;
     }
 
     fn action(&mut self, a:&Vec2, w:&World, c_info:Option<&ControlInfo>) -> (State, f64, State) {
@@ -72,51 +332,229 @@ impl<'a> Agent<'a> {
         let s = *ss;
         let x_max = (w.size.0 - 1) as i32;
         let y_max = (w.size.1 - 1) as i32;
-        let wind = w.wind[s.0 as usize] + 
+        let (wind_x, wind_y) = w.wind.get(&s).copied().unwrap_or((0, 0));
+        let wind = wind_y +
             match c_info {
-                Some(v) => {
-                    if v.stochastic_wind {
-                        let r:f64 = self.rng.gen();
-                        if r < 0.33 { -1 }
-                        else if r < 0.66 { 1 }
-                        else { 0 }
-                    }
+                Some(v) if v.stochastic_wind => {
+                    let r:f64 = self.rng.gen();
+                    if r < 0.33 { -1 }
+                    else if r < 0.66 { 1 }
                     else { 0 }
                 },
-                None => { 0 }
+                _ => { 0 }
+            };
+        let p = (max(0, min(s.0 + a.0 + wind_x, x_max)), max(0, min(s.1 + a.1 + wind, y_max)));
+        let r = if w.block.contains(&p) { self.info.step_reward }
+            else {
+                *ss = if w.hazard.contains(&p) { w.start } else { p };
+                w.reward.get(&p).copied().unwrap_or(self.info.step_reward)
             };
-        ss.0 = max(0, min(ss.0 + a.0, x_max));
-        ss.1 = max(0, min(ss.1 + a.1 + wind, y_max));
-        (s, self.info.step_reward, *ss)
+        self.ret += r;
+        (s, r, *ss)
     }
 }
 
 impl<'a> Graph<'a> {
-    fn new(p_ref:&'a mut Policy, w:&World) -> Self {
-        Self { q:NdVec2::from_size(w.size), p_ref }
+    fn new(p_ref:&'a mut Policy, a_info:&'a AgentInfo, w:&World) -> Self {
+        Self {
+            q:NdVec2::from_size(w.size), q2:NdVec2::from_size(w.size), e:NdVec2::from_size(w.size),
+            model:Model::new(), p_ref, a_info,
+        }
     }
 
+    //every table gets the shape of the action grid
     fn fill_q(&mut self, w:&World) {
-        let q = &mut self.q;
         let c = w.size.0 * w.size.1;
-        for _ in 0..c {
-            let mut a:NdVec2<f64> = NdVec2::new((-1, 1), (-1, 1));
+        let (o, d) = (self.a_info.action.offset, self.a_info.action.dimension);
+        let zero = || {
+            let mut a:NdVec2<f64> = NdVec2::new((-o.0, d.0 - o.0 - 1), (-o.1, d.1 - o.1 - 1));
             a.fill(0.0);
-            q.push(a);
+            a
+        };
+        for _ in 0..c {
+            self.q.push(zero());
+            self.q2.push(zero());
+            self.e.push(zero());
+        }
+    }
+
+    fn clear_trace(&mut self) {
+        self.e.iter_mut().flat_map(|e| e.iter_mut()).for_each(|v| *v = 0.0);
+    }
+
+    //decays every trace by gamma * lambda, then marks (s, a) as visited
+    fn visit_trace(&mut self, s:&State, a:&Action, c_info:&ControlInfo) {
+        let decay = c_info.gamma * c_info.lambda;
+        for e in self.e.iter_mut().flat_map(|e| e.iter_mut()) {
+            *e *= decay;
+            if *e < c_info.trace_min { *e = 0.0 }
+        }
+        let trace = match c_info.algorithm {
+            Algorithm::TrueOnlineSarsa => Trace::Dutch,
+            _ => c_info.trace,
+        };
+        let e = &mut self.e[s][a];
+        *e = match trace {
+            Trace::Accumulating => *e + 1.0,
+            Trace::Replacing => 1.0,
+            Trace::Dutch => (1.0 - c_info.alpha) * *e + 1.0,
+        };
+    }
+
+    //q += step * e everywhere, the greedy policy follows in every traced state
+    fn update_trace(&mut self, step:f64) {
+        for i in 0..self.q.data.len() {
+            let e = &self.e.data[i];
+            if e.iter().all(|v| *v == 0.0) { continue }
+            for (q, e) in self.q.data[i].iter_mut().zip(e.iter()) {
+                *q += step * e;
+            }
+            let s = self.q.rev_index(i);
+            self.update_policy(&s);
+        }
+    }
+
+    fn q_max(&self, s:&State) -> (usize, f64) {
+        let q = &self.q[s];
+        self.a_info.actions(s).map(|k| (k, q.data[k]))
+            .max_by(|(_, q0), (_, q1)| q0.total_cmp(q1)).unwrap()
+    }
+
+    //expectation under the epsilon-greedy policy of select_action
+    fn q_expected(&self, s:&State, c_info:&ControlInfo) -> f64 {
+        let q = &self.q[s];
+        self.a_info.actions(s)
+            .map(|k| q.data[k] * self.p_ref.probability(s, k, c_info, self.a_info))
+            .sum()
+    }
+
+    //greedy target policy of the off-policy n-step algorithms
+    fn p_target(&self, s:&State, k:usize) -> f64 {
+        if self.a_info.action.index(&self.p_ref.p[s]) == k { 1.0 } else { 0.0 }
+    }
+
+    //n-step return of (s[tau], a[tau]),
+    //r[i]:reward after a[i - 1], tt:terminal step or usize::MAX while running
+    fn n_step_return(&self, s:&[State], a:&[Action], r:&[f64], tau:usize, tt:usize, c_info:&ControlInfo) -> f64 {
+        let n = max(c_info.n, 1);
+        let gamma = c_info.gamma;
+        let index = |a:&Action| self.a_info.action.index(a);
+        let end = min(tau + n, tt);
+        match c_info.algorithm {
+            Algorithm::TreeBackup => {
+                //t + 1 = end
+                let mut g = r[end];
+                if end < tt {
+                    let ss = &s[end];
+                    g += gamma * self.a_info.actions(ss).map(|k| self.p_target(ss, k) * self.q[ss].data[k]).sum::<f64>();
+                }
+                for k in (tau + 1..end).rev() {
+                    let (sk, ak) = (&s[k], index(&a[k]));
+                    let others:f64 = self.a_info.actions(sk).filter(|i| *i != ak)
+                        .map(|i| self.p_target(sk, i) * self.q[sk].data[i]).sum();
+                    g = r[k] + gamma * others + gamma * self.p_target(sk, ak) * g;
+                }
+                g
+            },
+            _ => {
+                let mut g = if end < tt { self.q[s[end]][a[end]] } else { 0.0 };
+                for i in (tau + 1..=end).rev() {
+                    g = r[i] + gamma * g;
+                }
+                g
+            },
+        }
+    }
+
+    //importance ratio of the n-step return of (s[tau], a[tau]), 1 unless off-policy,
+    //b[i]:probability a[i] was selected with, 0 when the greedy target never takes a[i]
+    fn n_step_rho(&self, s:&[State], a:&[Action], b:&[f64], tau:usize, tt:usize, c_info:&ControlInfo) -> f64 {
+        let Algorithm::NStepOffPolicySarsa = c_info.algorithm else { return 1.0 };
+        let n = max(c_info.n, 1);
+        let mut rho = 1.0;
+        for i in tau + 1..min(tau + n + 1, tt) {
+            let p = self.p_target(&s[i], self.a_info.action.index(&a[i]));
+            if p == 0.0 || b[i] <= 0.0 { return 0.0 }
+            rho *= p / b[i];
+        }
+        rho
+    }
+
+    //next:(ss, aa), None when ss is terminal and the target is just r
+    fn update(&mut self, s:&State, a:&Action, r:f64, next:Option<(&State, &Action)>, c_info:&ControlInfo) {
+        // println!("{:?} {:?} {} {:?}", s, a, r, next);
+        let qq = match next {
+            Some((ss, aa)) => match c_info.algorithm {
+                Algorithm::QLearning | Algorithm::DynaQ | Algorithm::DynaQPlus => self.q_max(ss).1,
+                Algorithm::ExpectedSarsa => self.q_expected(ss, c_info),
+                _ => self.q[ss][aa],
+            },
+            None => 0.0,
+        };
+        let q = &mut self.q[s][a];
+        *q += c_info.alpha * (r + c_info.gamma * qq - *q);
+    }
+
+    //one Dyna update from the model, Dyna-Q+ picks any action of a visited state
+    fn plan(&mut self, c_info:&ControlInfo, rng:&mut StdRng) {
+        let (s, a) = match c_info.algorithm {
+            Algorithm::DynaQPlus => {
+                let s = *self.model.state.choose(rng).unwrap();
+                let k = self.a_info.actions(&s).choose(rng).unwrap();
+                (s, self.a_info.action.rev_index(k))
+            },
+            _ => *self.model.key.choose(rng).unwrap(),
+        };
+        //an action never tried stays in s at the step reward, stale since the start
+        let (ss, mut r, done, tau) = self.model.sample(&s, &a, rng)
+            .unwrap_or((s, self.a_info.step_reward, false, self.model.time));
+        if let Algorithm::DynaQPlus = c_info.algorithm {
+            r += c_info.kappa * (tau as f64).sqrt();
         }
+        self.update(&s, &a, r, if done { None } else { Some((&ss, &a)) }, c_info);
+        self.update_policy(&s);
     }
 
-    fn update(&mut self, s:&State, a:&Action, r:f64, ss:&State, aa:&Action, c_info:&ControlInfo) {
-        // println!("{:?} {:?} {} {:?} {:?}", s, a, r, ss, aa);
-        let qq = self.q[ss][aa];
+    //the updated table q is valued by q2, the tables trade roles on swap
+    fn update_double(&mut self, s:&State, a:&Action, r:f64, next:Option<&State>, c_info:&ControlInfo, swap:bool) {
+        if swap { mem::swap(&mut self.q, &mut self.q2) }
+        let qq = match next {
+            Some(ss) => {
+                let (k_max, _) = self.q_max(ss);
+                let q2 = &self.q2[ss];
+                match c_info.algorithm {
+                    Algorithm::DoubleExpectedSarsa => {
+                        let p_explore = c_info.epsilon / self.a_info.actions(ss).count() as f64;
+                        self.a_info.actions(ss)
+                            .map(|k| q2.data[k] * if k == k_max { 1.0 - c_info.epsilon + p_explore } else { p_explore })
+                            .sum()
+                    },
+                    _ => q2.data[k_max],
+                }
+            },
+            None => 0.0,
+        };
         let q = &mut self.q[s][a];
-        *q += c_info.alpha * (r + qq - *q);
+        *q += c_info.alpha * (r + c_info.gamma * qq - *q);
+        if swap { mem::swap(&mut self.q, &mut self.q2) }
+    }
+
+    //one-step update of (s, a) and its greedy action, the double algorithms flip a coin for the table
+    fn learn(&mut self, s:&State, a:&Action, r:f64, next:Option<(&State, &Action)>, c_info:&ControlInfo, rng:&mut StdRng) {
+        match c_info.algorithm {
+            Algorithm::DoubleQLearning | Algorithm::DoubleExpectedSarsa =>
+                self.update_double(s, a, r, next.map(|(ss, _)| ss), c_info, rng.gen()),
+            _ => self.update(s, a, r, next, c_info),
+        }
+        self.update_policy(s);
     }
 
-    fn update_policy(&mut self, s:&State, a_info:&AgentInfo) {
-        let (a, _) = self.q[s].iter().enumerate()
-            .max_by(|(_, q0), (_, q1)| q0.total_cmp(q1)).unwrap();
-        self.p_ref.p[s] = a_info.action.rev_index(a);
+    //greedy on q + q2
+    fn update_policy(&mut self, s:&State) {
+        let (q, q2) = (&self.q[s], &self.q2[s]);
+        let a = self.a_info.actions(s)
+            .max_by(|k0, k1| (q.data[*k0] + q2.data[*k0]).total_cmp(&(q.data[*k1] + q2.data[*k1]))).unwrap();
+        self.p_ref.p[s] = self.a_info.action.rev_index(a);
     }
 
     fn print_policy_sample(&self, w:&World, a_info:&AgentInfo) {
@@ -124,7 +562,7 @@ impl<'a> Graph<'a> {
         let map = &self.p_ref.p;
         let mut visit:HashSet<Vec2> = HashSet::new();
         visit.insert(w.start);
-        let mut agent = Agent::new(a_info);
+        let mut agent = Agent::new(a_info, 0);
         agent.reset(&w.start);
         let (finish, s) = loop {
             let s = agent.state();
@@ -149,7 +587,10 @@ impl<'a> Graph<'a> {
         let size = &w.size;
         for y in (0..size.1).rev() {
             for x in 0..size.0 {
-                if visit.contains(&(x as i32, y as i32)) { print!("|+|") }
+                let p = (x as i32, y as i32);
+                if visit.contains(&p) { print!("|+|") }
+                else if w.block.contains(&p) { print!("|#|") }
+                else if w.hazard.contains(&p) { print!("|H|") }
                 else { print!("| |"); }
             }
             println!();
@@ -157,6 +598,41 @@ impl<'a> Graph<'a> {
     }
 }
 
+impl Model {
+    fn new() -> Self {
+        Self { transition:HashMap::new(), key:Vec::new(), state:Vec::new(), time:0 }
+    }
+
+    fn learn(&mut self, s:&State, a:&Action, r:f64, ss:&State, done:bool, kind:ModelKind) {
+        self.time += 1;
+        let sa = (*s, *a);
+        if !self.transition.contains_key(&sa) {
+            self.key.push(sa);
+            if !self.state.contains(s) { self.state.push(*s) }
+        }
+        let (outcome, time) = self.transition.entry(sa).or_insert((Vec::new(), 0));
+        *time = self.time;
+        match kind {
+            ModelKind::Deterministic => *outcome = vec![(*ss, r, done, 1)],
+            ModelKind::Sampled => match outcome.iter_mut().find(|o| o.0 == *ss && o.1 == r && o.2 == done) {
+                Some(o) => o.3 += 1,
+                None => outcome.push((*ss, r, done, 1)),
+            },
+        }
+    }
+
+    //(next state, reward, terminal, steps since the last real visit), None when never tried
+    fn sample(&self, s:&State, a:&Action, rng:&mut StdRng) -> Option<(State, f64, bool, usize)> {
+        let (outcome, time) = self.transition.get(&(*s, *a))?;
+        let mut k = rng.gen_range(0..outcome.iter().map(|o| o.3).sum::<usize>());
+        for o in outcome.iter() {
+            if k < o.3 { return Some((o.0, o.1, o.2, self.time - time)) }
+            k -= o.3;
+        }
+        None
+    }
+}
+
 impl Policy {
     fn new(w:&World) -> Self {
         Self { p:NdVec2::from_size(w.size) }
@@ -166,74 +642,631 @@ impl Policy {
         let c = w.size.0 * w.size.1;
         let rng = &mut agent.rng;
         let p = &mut self.p;
-        for _ in 0..c {
-            let a = Policy::random_action(agent.info, rng);
+        for k in 0..c {
+            let a = Policy::random_action(&p.rev_index(k), agent.info, rng);
             // println!(" {} {:?}", k, a);
             p.push(a);
         }
     }
 
-    fn random_action(a_info:&AgentInfo, rng:&mut ThreadRng) -> Action {
-        let r = if a_info.no_stay { 8.0 } else { 9.0 };//8-dir move
-        let rn:f64 = rng.gen();
-        let mut r = (rn * r).floor() as i32;
-        if a_info.no_stay && r >= 4 { r += 1 }//skip (0, 0)
-        a_info.action.rev_index(r as usize)
+    fn random_action(s:&State, a_info:&AgentInfo, rng:&mut StdRng) -> Action {
+        let k = a_info.actions(s).choose(rng).unwrap();
+        a_info.action.rev_index(k)
     }
 
-    fn select_action(&self, s:&State, c_info:&ControlInfo, a_info:&AgentInfo, rng:&mut ThreadRng) -> Action {
+    fn select_action(&self, s:&State, c_info:&ControlInfo, a_info:&AgentInfo, rng:&mut StdRng) -> Action {
         let rn:f64 = rng.gen();
-        if rn < c_info.epsilon { Policy::random_action(a_info, rng) }
+        if rn < c_info.epsilon { Policy::random_action(s, a_info, rng) }
         else { self.p[*s] }
     }
+
+    //of taking action index k in select_action
+    fn probability(&self, s:&State, k:usize, c_info:&ControlInfo, a_info:&AgentInfo) -> f64 {
+        if !a_info.actions(s).any(|i| i == k) { return 0.0 }
+        let p_explore = c_info.epsilon / a_info.actions(s).count() as f64;
+        if a_info.action.index(&self.p[s]) == k { 1.0 - c_info.epsilon + p_explore }
+        else { p_explore }
+    }
+}
+
+//returns the number of steps
+fn episode(c_info:&ControlInfo, agent:&mut Agent, w:&mut World, g:&mut Graph) -> usize {
+    match c_info.algorithm {
+        Algorithm::NStepSarsa | Algorithm::NStepOffPolicySarsa | Algorithm::TreeBackup => episode_n(c_info, agent, w, g),
+        Algorithm::SarsaLambda | Algorithm::TrueOnlineSarsa => episode_lambda(c_info, agent, w, g),
+        Algorithm::DynaQ | Algorithm::DynaQPlus => episode_dyna(c_info, agent, w, g),
+        _ => episode_td(c_info, agent, w, g),
+    }
 }
 
-fn episode(c_info:&ControlInfo, agent:&mut Agent, w:&mut World, g:&mut Graph) {
+//one-step updates while moving
+fn episode_td(c_info:&ControlInfo, agent:&mut Agent, w:&mut World, g:&mut Graph) -> usize {
     agent.reset(&w.start);
     let mut a = g.p_ref.select_action(&agent.position, c_info, agent.info, &mut agent.rng);
+    let mut step = 0;
     loop {
         let (s, r, ss) = agent.action(&a, w, Some(c_info));
+        w.tick();
         let aa = g.p_ref.select_action(&ss, c_info, agent.info, &mut agent.rng);
-        g.update(&s, &a, r, &ss, &aa, c_info);
-        g.update_policy(&s, agent.info);
+        let done = w.is_terminal(&ss);
+        g.learn(&s, &a, r, if done { None } else { Some((&ss, &aa)) }, c_info, &mut agent.rng);
         a = aa;
-        if w.is_terminal(&ss) { break }
+        step += 1;
+        if done { break }
     }
+    step
 }
 
 fn iteration(c_info:&ControlInfo, agent:&mut Agent, w:&mut World, g:&mut Graph) {
     let mut ep_c = 0;
     let interval = c_info.max_episode / c_info.episode_check;
     while ep_c < c_info.max_episode {
-        let mut ep_cc = 0;
-        while ep_cc < interval {
-            episode(c_info, agent, w, g);
-            ep_cc += 1;
+        let mut log = RunLog::new();
+        while log.length.len() < interval {
+            let step = episode(c_info, agent, w, g);
+            log.push(step, agent.ret);
         }
+        ep_c += interval;
+        let n = interval as f64;
+        println!("\nepisodes:{} length mean:{:.1} return mean:{:.1}", ep_c,
+            log.length.iter().sum::<f64>() / n, log.ret.iter().sum::<f64>() / n);
         g.print_policy_sample(w, agent.info);
-        ep_c += ep_cc;
+    }
+}
+
+//updates lag n steps behind, then the remaining ones after the goal
+fn episode_n(c_info:&ControlInfo, agent:&mut Agent, w:&mut World, g:&mut Graph) -> usize {
+    let n = max(c_info.n, 1);
+    agent.reset(&w.start);
+    let mut s = vec![agent.position];
+    let mut a = vec![g.p_ref.select_action(&s[0], c_info, agent.info, &mut agent.rng)];
+    //b(a|s) when each action is selected, q and the policy move on before the ratio is taken
+    let mut b = vec![g.p_ref.probability(&s[0], agent.info.action.index(&a[0]), c_info, agent.info)];
+    let mut r = vec![0.0];
+    let mut tt = usize::MAX;
+    let mut t = 0;
+    loop {
+        if t < tt {
+            let (_, rr, ss) = agent.action(&a[t], w, Some(c_info));
+            w.tick();
+            r.push(rr);
+            s.push(ss);
+            if w.is_terminal(&ss) { tt = t + 1 }
+            else {
+                let aa = g.p_ref.select_action(&ss, c_info, agent.info, &mut agent.rng);
+                b.push(g.p_ref.probability(&ss, agent.info.action.index(&aa), c_info, agent.info));
+                a.push(aa);
+            }
+        }
+        if t + 1 >= n {
+            let tau = t + 1 - n;
+            let g_tau = g.n_step_return(&s, &a, &r, tau, tt, c_info);
+            let rho = g.n_step_rho(&s, &a, &b, tau, tt, c_info);
+            let q = &mut g.q[s[tau]][a[tau]];
+            *q += c_info.alpha * rho * (g_tau - *q);
+            g.update_policy(&s[tau]);
+            if tau + 1 == tt { break }
+        }
+        t += 1;
+    }
+    tt
+}
+
+//backward view, traces reset every episode
+fn episode_lambda(c_info:&ControlInfo, agent:&mut Agent, w:&mut World, g:&mut Graph) -> usize {
+    g.clear_trace();
+    agent.reset(&w.start);
+    let mut a = g.p_ref.select_action(&agent.position, c_info, agent.info, &mut agent.rng);
+    let mut q_old = 0.0;
+    let mut step = 0;
+    loop {
+        let (s, r, ss) = agent.action(&a, w, Some(c_info));
+        w.tick();
+        let aa = g.p_ref.select_action(&ss, c_info, agent.info, &mut agent.rng);
+        let done = w.is_terminal(&ss);
+        let q = g.q[s][a];
+        let qq = if done { 0.0 } else { g.q[ss][aa] };
+        let delta = r + c_info.gamma * qq - q;
+        g.visit_trace(&s, &a, c_info);
+        if let Algorithm::TrueOnlineSarsa = c_info.algorithm {
+            g.update_trace(c_info.alpha * (delta + q - q_old));
+            g.q[s][a] -= c_info.alpha * (q - q_old);
+            g.update_policy(&s);
+            q_old = qq;
+        }
+        else {
+            g.update_trace(c_info.alpha * delta);
+        }
+        a = aa;
+        step += 1;
+        if done { break }
+    }
+    step
+}
+
+//Q-learning on real steps, then planning updates from the model
+fn episode_dyna(c_info:&ControlInfo, agent:&mut Agent, w:&mut World, g:&mut Graph) -> usize {
+    agent.reset(&w.start);
+    let mut step = 0;
+    loop {
+        let a = g.p_ref.select_action(&agent.position, c_info, agent.info, &mut agent.rng);
+        let (s, r, ss) = agent.action(&a, w, Some(c_info));
+        w.tick();
+        let done = w.is_terminal(&ss);
+        g.update(&s, &a, r, if done { None } else { Some((&ss, &a)) }, c_info);
+        g.update_policy(&s);
+        g.model.learn(&s, &a, r, &ss, done, c_info.model);
+        for _ in 0..c_info.planning {
+            g.plan(c_info, &mut agent.rng);
+        }
+        step += 1;
+        if done { break }
+    }
+    step
+}
+
+//length and return of every episode from a fresh start, one log per run
+fn learning_log(c_info:&ControlInfo, a_info:&AgentInfo, w:&mut World, runs:usize) -> Vec<RunLog> {
+    (0..runs).map(|run| {
+        let mut agent = Agent::new(a_info, c_info.seed + run as u64);
+        let mut pi = Policy::new(w);
+        pi.fill_random(w, &mut agent);
+        let mut g = Graph::new(&mut pi, a_info, w);
+        g.fill_q(w);
+        let mut log = RunLog::new();
+        for _ in 0..c_info.max_episode {
+            let step = episode(c_info, &mut agent, w, &mut g);
+            log.push(step, agent.ret);
+        }
+        log
+    }).collect()
+}
+
+//episodes finished by each time step, averaged over runs, a fresh copy of w each run
+fn cumulative_episode(c_info:&ControlInfo, a_info:&AgentInfo, w:&World, runs:usize, steps:usize) -> Vec<f64> {
+    let mut curve = vec![0.0; steps];
+    for run in 0..runs {
+        let mut w = w.clone();
+        let mut agent = Agent::new(a_info, c_info.seed + run as u64);
+        let mut pi = Policy::new(&w);
+        pi.fill_random(&mut w, &mut agent);
+        let mut g = Graph::new(&mut pi, a_info, &w);
+        g.fill_q(&w);
+        let mut ep = 0;
+        while w.time < steps {
+            let t = w.time;
+            episode(c_info, &mut agent, &mut w, &mut g);
+            for c in curve[t..min(w.time, steps)].iter_mut() {
+                *c += ep as f64 / runs as f64;
+            }
+            ep += 1;
+            if w.time <= steps { curve[w.time - 1] += 1.0 / runs as f64 }
+        }
+    }
+    curve
+}
+
+impl BiasMdp {
+    const A:State = (1, 0);
+    const B:State = (0, 0);
+    const T:State = (2, 0);
+
+    fn world(&self) -> World {
+        World {
+            size:(3, 1), start:BiasMdp::A, goal:HashSet::from([BiasMdp::T]),
+            hazard:HashSet::new(), reward:HashMap::new(), wind:HashMap::new(),
+            block:HashSet::new(), change:None, time:0,
+        }
+    }
+
+    //action (0, 0) of A goes left, (1, 0) right
+    fn agent_info(&self) -> AgentInfo {
+        AgentInfo {
+            action:NdVec2::new((0, self.b_action as i32 - 1), (0, 0)),
+            no_stay:false, step_reward:0.0,
+            limit:HashMap::from([(BiasMdp::A, 2)]),
+        }
+    }
+
+    fn step(&self, s:&State, a:&Action, rng:&mut StdRng) -> (f64, State) {
+        if *s == BiasMdp::A {
+            return (0.0, if a.0 == 0 { BiasMdp::B } else { BiasMdp::T })
+        }
+        //Box-Muller
+        let (u0, u1):(f64, f64) = (rng.gen(), rng.gen());
+        let z = (-2.0 * (1.0 - u0).ln()).sqrt() * (2.0 * PI * u1).cos();
+        (self.mean + self.sd * z, BiasMdp::T)
+    }
+}
+
+//one episode of the bias MDP, true when A went left
+fn episode_bias(c_info:&ControlInfo, agent:&mut Agent, mdp:&BiasMdp, g:&mut Graph) -> bool {
+    let mut s = BiasMdp::A;
+    let mut a = g.p_ref.select_action(&s, c_info, agent.info, &mut agent.rng);
+    let mut left = false;
+    loop {
+        let (r, ss) = mdp.step(&s, &a, &mut agent.rng);
+        if s == BiasMdp::A { left = ss == BiasMdp::B }
+        let aa = g.p_ref.select_action(&ss, c_info, agent.info, &mut agent.rng);
+        let done = ss == BiasMdp::T;
+        g.learn(&s, &a, r, if done { None } else { Some((&ss, &aa)) }, c_info, &mut agent.rng);
+        if done { break }
+        (s, a) = (ss, aa);
+    }
+    left
+}
+
+//share of left actions in A per episode, averaged over runs
+fn bias_curve(c_info:&ControlInfo, mdp:&BiasMdp, runs:usize, episodes:usize) -> Vec<f64> {
+    let a_info = mdp.agent_info();
+    let mut w = mdp.world();
+    let mut curve = vec![0.0; episodes];
+    for run in 0..runs {
+        let mut agent = Agent::new(&a_info, c_info.seed + run as u64);
+        let mut pi = Policy::new(&w);
+        pi.fill_random(&mut w, &mut agent);
+        let mut g = Graph::new(&mut pi, &a_info, &w);
+        g.fill_q(&w);
+        for c in curve.iter_mut() {
+            if episode_bias(c_info, &mut agent, mdp, &mut g) { *c += 1.0 / runs as f64 }
+        }
+    }
+    curve
+}
+
+impl Figure for MazeFigure {
+    fn draw<DB:DrawingBackend>(&self, _k:usize, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+        where DB::ErrorType: 'static {
+        let steps = self.curve.iter().map(|(_, c)| c.len()).max().unwrap_or(0);
+        let ep_max = self.curve.iter().flat_map(|(_, c)| c.iter()).fold(1.0f64, |m, v| m.max(*v)) * 1.05;
+        let mut chart = ChartBuilder::on(canvas)
+            .margin(10)
+            .caption(format!("{:?} maze", self.maze), ("sans-serif", 24))
+            .x_label_area_size(50)
+            .y_label_area_size(60)
+            .build_cartesian_2d(0..steps, 0.0..ep_max)?;
+        chart.configure_mesh().x_desc("time step").y_desc("cumulative reward").draw()?;
+        chart.draw_series(LineSeries::new(vec![(self.change, 0.0), (self.change, ep_max)], BLACK.mix(0.6).stroke_width(2)))?;
+        for (k, (name, c)) in self.curve.iter().enumerate() {
+            let color = Palette99::pick(k).to_rgba();
+            chart.draw_series(LineSeries::new(c.iter().enumerate().map(|(i, v)| (i, *v)), color.stroke_width(2)))?
+                .label(name)
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color.stroke_width(2)));
+        }
+        chart.configure_series_labels()
+            .position(SeriesLabelPosition::UpperLeft)
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()?;
+        Ok(())
+    }
+}
+
+impl Figure for BiasFigure {
+    fn draw<DB:DrawingBackend>(&self, _k:usize, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+        where DB::ErrorType: 'static {
+        let episodes = self.curve.iter().map(|(_, c)| c.len()).max().unwrap_or(0);
+        let mut chart = ChartBuilder::on(canvas)
+            .margin(10)
+            .x_label_area_size(50)
+            .y_label_area_size(60)
+            .build_cartesian_2d(0..episodes, 0.0..100.0)?;
+        chart.configure_mesh().x_desc("episode").y_desc("% left actions from A").draw()?;
+        chart.draw_series(LineSeries::new(vec![(0, self.optimal * 100.0), (episodes, self.optimal * 100.0)], BLACK.mix(0.6).stroke_width(2)))?
+            .label("optimal")
+            .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], BLACK.mix(0.6).stroke_width(2)));
+        for (k, (name, c)) in self.curve.iter().enumerate() {
+            let color = Palette99::pick(k).to_rgba();
+            chart.draw_series(LineSeries::new(c.iter().enumerate().map(|(i, v)| (i, v * 100.0)), color.stroke_width(2)))?
+                .label(name)
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color.stroke_width(2)));
+        }
+        chart.configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()?;
+        Ok(())
+    }
+}
+
+fn n_alpha_sweep(c_info:&ControlInfo, a_info:&AgentInfo, w:&mut World, runs:usize, episodes:usize) -> NAlphaFigure {
+    let sweep = [Algorithm::NStepSarsa, Algorithm::NStepOffPolicySarsa, Algorithm::TreeBackup].iter()
+        .map(|algorithm| {
+            let line = [1, 2, 4, 8, 16].iter().map(|n| {
+                let point = (1..=10).map(|k| {
+                    let alpha = k as f64 * 0.1;
+                    let c_info = ControlInfo { algorithm:*algorithm, n:*n, alpha, max_episode:episodes, ..*c_info };
+                    let log = learning_log(&c_info, a_info, w, runs);
+                    (alpha, log.iter().flat_map(|l| l.length.iter()).sum::<f64>() / (runs * episodes) as f64)
+                }).collect();
+                (*n, point)
+            }).collect();
+            (*algorithm, line)
+        }).collect();
+    NAlphaFigure { sweep }
+}
+
+impl Figure for NAlphaFigure {
+    fn draw<DB:DrawingBackend>(&self, _k:usize, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+        where DB::ErrorType: 'static {
+        let panel = canvas.split_evenly((1, self.sweep.len()));
+        for ((algorithm, line), canvas) in self.sweep.iter().zip(panel.iter()) {
+            let step_max = line.iter().flat_map(|(_, p)| p.iter()).fold(1.0f64, |m, (_, v)| m.max(*v));
+            let mut chart = ChartBuilder::on(canvas)
+                .margin(10)
+                .caption(format!("{:?}", algorithm), ("sans-serif", 24))
+                .x_label_area_size(50)
+                .y_label_area_size(60)
+                .build_cartesian_2d(0.0..1.05, (1.0..step_max * 1.1).log_scale())?;
+            chart.configure_mesh().x_desc("alpha").y_desc("mean steps per episode").draw()?;
+            for (k, (n, p)) in line.iter().enumerate() {
+                let color = Palette99::pick(k).to_rgba();
+                chart.draw_series(LineSeries::new(p.iter().copied(), color.stroke_width(2)))?
+                    .label(format!("n = {}", n))
+                    .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color.stroke_width(2)));
+            }
+            chart.configure_series_labels()
+                .background_style(WHITE.mix(0.8))
+                .border_style(BLACK)
+                .draw()?;
+        }
+        Ok(())
     }
 }
 
 pub fn run() -> Result<(), Box<dyn Error>> {
     let c_info = ControlInfo {
         max_episode:2000, episode_check:50,
-        epsilon:0.1, alpha:0.5,
-        stochastic_wind:true,
+        epsilon:0.1, alpha:0.5, gamma:1.0, n:4,
+        lambda:0.9, trace:Trace::Replacing, trace_min:1e-4,
+        planning:10, kappa:0.01, model:ModelKind::Sampled,
+        stochastic_wind:true, map:None, // Some("map/cliff.txt"),
+        algorithm:Algorithm::Sarsa,
+        curve_run:0,// 20,
+        seed:0, figure:Backend::Png,
     };
-    let mut w = World {
-        size:(10, 7), start:(0, 3), goal:(7, 3),
-        wind:vec!(0, 0, 0, 1, 1, 1, 2, 2, 1, 0),
+    let mut w = match c_info.map {
+        Some(path) => World::load(path)?,
+        None => World::windy(),
     };
     let a_info = AgentInfo {
         action:NdVec2::new((-1, 1), (-1, 1)),
-        step_reward:-1.0, no_stay:false,
+        step_reward:-1.0, no_stay:false, limit:HashMap::new(),
     };
-    let mut agent = Agent::new(&a_info);
+    let mut agent = Agent::new(&a_info, c_info.seed);
     let mut pi = Policy::new(&w);
     pi.fill_random(&mut w, &mut agent);
-    let mut g = Graph::new(&mut pi, &w);
+    let mut g = Graph::new(&mut pi, &a_info, &w);
     g.fill_q(&w);
     iteration(&c_info, &mut agent, &mut w, &mut g);
+    if c_info.curve_run > 0 {
+        let mut variant:Vec<(String, ControlInfo)> = [Algorithm::Sarsa, Algorithm::QLearning, Algorithm::ExpectedSarsa,
+            Algorithm::NStepSarsa, Algorithm::NStepOffPolicySarsa, Algorithm::TreeBackup, Algorithm::TrueOnlineSarsa,
+            Algorithm::DynaQ, Algorithm::DynaQPlus, Algorithm::DoubleQLearning, Algorithm::DoubleExpectedSarsa].iter()
+            .map(|algorithm| (format!("{:?}", algorithm), ControlInfo { algorithm:*algorithm, ..c_info }))
+            .collect();
+        for trace in [Trace::Accumulating, Trace::Replacing, Trace::Dutch] {
+            variant.push((format!("SarsaLambda {:?}", trace), ControlInfo { algorithm:Algorithm::SarsaLambda, trace, ..c_info }));
+        }
+        let curve:Vec<LearningCurve> = variant.iter()
+            .map(|(name, c_info)| LearningCurve::new(name, &learning_log(c_info, &a_info, &mut w, c_info.curve_run)))
+            .collect();
+        write_csv("6_9_curve.csv", &curve)?;
+        c_info.figure.render("6_9_curve", (2160, 720), 0, &LearningFigure { curve:&curve })?;
+    }
+    //n-step sweep switch: (runs, episodes)
+    let n_alpha:Option<(usize, usize)> =
+        None;
+        // Some((10, 100));
+    if let Some((runs, episodes)) = n_alpha {
+        let fig = n_alpha_sweep(&c_info, &a_info, &mut w, runs, episodes);
+        c_info.figure.render("6_9_n_alpha", (2160, 720), 0, &fig)?;
+    }
+    //maximisation bias switch: (runs, episodes)
+    let bias:Option<(usize, usize)> =
+        None;
+        // Some((10000, 300));
+    if let Some((runs, episodes)) = bias {
+        let mdp = BiasMdp { b_action:10, mean:-0.1, sd:1.0 };
+        let c_info = ControlInfo { epsilon:0.1, alpha:0.1, gamma:1.0, ..c_info };
+        let curve = [Algorithm::QLearning, Algorithm::DoubleQLearning, Algorithm::ExpectedSarsa, Algorithm::DoubleExpectedSarsa].iter()
+            .map(|algorithm| {
+                let c_info = ControlInfo { algorithm:*algorithm, ..c_info };
+                let c = bias_curve(&c_info, &mdp, runs, episodes);
+                println!("{:?} left from A: {:.1}% in the last episode", algorithm, c[episodes - 1] * 100.0);
+                (format!("{:?}", algorithm), c)
+            })
+            .collect();
+        let fig = BiasFigure { optimal:c_info.epsilon / 2.0, curve };
+        c_info.figure.render("6_9_bias", (1440, 720), 0, &fig)?;
+    }
+    //changing maze switch: runs
+    let maze:Option<usize> =
+        None;
+        // Some(20);
+    if let Some(runs) = maze {
+        //rewards of the book, 0 per step and 1 at the goal of the map files, kappa scaled down to match
+        let c_info = ControlInfo {
+            gamma:0.95, stochastic_wind:false, planning:50, kappa:0.0003, model:ModelKind::Deterministic, ..c_info
+        };
+        let a_info = AgentInfo { step_reward:0.0, ..a_info };
+        for maze in [Maze::Blocking, Maze::Shortcut] {
+            let (w, steps) = maze.world()?;
+            let change = w.change.as_ref().map_or(0, |(t, _)| *t);
+            let curve = [Algorithm::QLearning, Algorithm::DynaQ, Algorithm::DynaQPlus].iter()
+                .map(|algorithm| {
+                    let c_info = ControlInfo { algorithm:*algorithm, ..c_info };
+                    (format!("{:?}", algorithm), cumulative_episode(&c_info, &a_info, &w, runs, steps))
+                })
+                .collect();
+            let name = format!("6_9_{:?}", maze).to_lowercase();
+            c_info.figure.render(&name, (1440, 720), 0, &MazeFigure { maze, change, curve })?;
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAMMA:f64 = 0.9;
+    const STEP:f64 = -0.5;
+    const GOAL:f64 = 1.0;
+    const RIGHT:Action = (1, 0);
+
+    //a row 0 -> 1 -> 2 -> terminal 3, actions left, stay and right
+    fn chain() -> (World, AgentInfo) {
+        let w = World {
+            size:(4, 1), start:(0, 0), goal:HashSet::from([(3, 0)]),
+            hazard:HashSet::new(), reward:HashMap::from([((3, 0), GOAL)]), wind:HashMap::new(),
+            block:HashSet::new(), change:None, time:0,
+        };
+        let a_info = AgentInfo {
+            action:NdVec2::new((-1, 1), (0, 0)),
+            step_reward:STEP, no_stay:false, limit:HashMap::new(),
+        };
+        (w, a_info)
+    }
+
+    fn control(algorithm:Algorithm, epsilon:f64) -> ControlInfo {
+        ControlInfo {
+            max_episode:0, episode_check:0,
+            epsilon, alpha:0.5, gamma:GAMMA, n:1,
+            lambda:0.0, trace:Trace::Replacing, trace_min:0.0,
+            planning:0, kappa:0.0, model:ModelKind::Deterministic,
+            stochastic_wind:false, map:None,
+            algorithm,
+            curve_run:0, seed:0, figure:Backend::Png,
+        }
+    }
+
+    //q of moving right after repeated sweeps along the chain, the terminal q is garbage
+    //that the update must not bootstrap from
+    fn sweep(c_info:&ControlInfo) -> Vec<f64> {
+        let (mut w, a_info) = chain();
+        let mut agent = Agent::new(&a_info, 0);
+        let mut pi = Policy::new(&w);
+        pi.fill_random(&mut w, &mut agent);
+        let mut g = Graph::new(&mut pi, &a_info, &w);
+        g.fill_q(&w);
+        g.q[(3, 0)].iter_mut().for_each(|q| *q = 100.0);
+        for _ in 0..200 {
+            for x in 0..3 {
+                let (s, ss) = ((x, 0), (x + 1, 0));
+                if w.is_terminal(&ss) {
+                    g.update(&s, &RIGHT, GOAL, None, c_info);
+                } else {
+                    g.update(&s, &RIGHT, STEP, Some((&ss, &RIGHT)), c_info);
+                }
+                g.update_policy(&s);
+            }
+        }
+        (0..3).map(|x| g.q[(x, 0)][RIGHT]).collect()
+    }
+
+    //q(2) = goal, q(x) = step + gamma * c * q(x + 1), c:weight of the right action in the target
+    fn fixed_point(c:f64) -> Vec<f64> {
+        let mut q = vec![GOAL; 3];
+        for x in (0..2).rev() {
+            q[x] = STEP + GAMMA * c * q[x + 1];
+        }
+        q
+    }
+
+    fn assert_close(q:&[f64], expected:&[f64]) {
+        for (x, (v, e)) in q.iter().zip(expected.iter()).enumerate() {
+            assert!((v - e).abs() < 1e-9, "state {}: q {} expected {}", x, v, e);
+        }
+    }
+
+    #[test]
+    fn windy_map_matches_windy() {
+        let (m, w) = (World::load("map/windy.txt").unwrap(), World::windy());
+        assert_eq!(m.size, w.size);
+        assert_eq!(m.start, w.start);
+        assert_eq!(m.goal, w.goal);
+        assert_eq!(m.hazard, w.hazard);
+        assert_eq!(m.reward, w.reward);
+        assert_eq!(m.wind, w.wind);
+        assert_eq!(m.block, w.block);
+        assert!(m.change.is_none() && w.change.is_none());
+    }
+
+    #[test]
+    fn cliff_map_resets_at_hazard() {
+        let w = World::load("map/cliff.txt").unwrap();
+        assert_eq!((w.size, w.start, w.goal.clone()), ((12, 4), (0, 0), HashSet::from([(11, 0)])));
+        assert_eq!(w.hazard, (1..11).map(|x| (x, 0)).collect());
+        assert!(w.hazard.iter().all(|p| w.reward[p] == -100.0));
+        let a_info = AgentInfo {
+            action:NdVec2::new((-1, 1), (-1, 1)),
+            step_reward:-1.0, no_stay:false, limit:HashMap::new(),
+        };
+        let mut agent = Agent::new(&a_info, 0);
+        agent.reset(&(1, 1));
+        assert_eq!(agent.action(&(1, 0), &w, None), ((1, 1), -1.0, (2, 1)));
+        assert_eq!(agent.action(&(0, -1), &w, None), ((2, 1), -100.0, w.start));
+        assert_eq!(agent.ret, -101.0);
+    }
+
+    #[test]
+    fn map_rows_of_spaces_are_not_the_legend() {
+        let w = World::parse("..G\nS\n\nG goal 5").unwrap();
+        assert_eq!(w.block, HashSet::from([(1, 0), (2, 0)]));
+        assert_eq!(w.reward, HashMap::from([((2, 1), 5.0)]));
+        let e = World::parse("..G\n   \nS..").err().unwrap().to_string();
+        assert_eq!(e, "invalid map cell ' ' at line 2 column 1");
+    }
+
+    #[test]
+    fn update_reaches_fixed_point() {
+        //the right action's values stay above the untried 0s at the next states, so max picks it
+        for algorithm in [Algorithm::Sarsa, Algorithm::QLearning, Algorithm::DynaQ] {
+            assert_close(&sweep(&control(algorithm, 0.1)), &fixed_point(1.0));
+        }
+        //greedy right with probability 1 - epsilon + epsilon / 3, the others are 0
+        let epsilon = 0.3;
+        assert_close(&sweep(&control(Algorithm::ExpectedSarsa, epsilon)), &fixed_point(1.0 - epsilon + epsilon / 3.0));
+    }
+
+    #[test]
+    fn n_step_rho_uses_recorded_probability() {
+        let (mut w, a_info) = chain();
+        let mut agent = Agent::new(&a_info, 0);
+        let mut pi = Policy::new(&w);
+        pi.fill_random(&mut w, &mut agent);
+        let mut g = Graph::new(&mut pi, &a_info, &w);
+        g.fill_q(&w);
+        //b as recorded at selection, the policy changes afterwards
+        let c_info = ControlInfo { n:2, ..control(Algorithm::NStepOffPolicySarsa, 0.0) };
+        let (s, a, b) = ([(0, 0), (1, 0), (2, 0)], [RIGHT, RIGHT, RIGHT], [1.0, 0.5, 0.25]);
+        g.p_ref.p[(1, 0)] = RIGHT;
+        g.p_ref.p[(2, 0)] = RIGHT;
+        assert_eq!(g.n_step_rho(&s, &a, &b, 0, usize::MAX, &c_info), 8.0);
+        //the target no longer takes a[1], and b[2] of 0 must not divide
+        g.p_ref.p[(1, 0)] = (-1, 0);
+        assert_eq!(g.n_step_rho(&s, &a, &b, 0, usize::MAX, &c_info), 0.0);
+        g.p_ref.p[(1, 0)] = RIGHT;
+        assert_eq!(g.n_step_rho(&s, &a, &[1.0, 0.5, 0.0], 0, usize::MAX, &c_info), 0.0);
+        //terminal at step 2 leaves only a[1], on-policy algorithms have no ratio
+        assert_eq!(g.n_step_rho(&s, &a, &b, 0, 2, &c_info), 2.0);
+        assert_eq!(g.n_step_rho(&s, &a, &b, 0, usize::MAX, &control(Algorithm::NStepSarsa, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn update_terminal_target_is_reward() {
+        let c_info = control(Algorithm::Sarsa, 0.0);
+        let q = sweep(&c_info);
+        assert!((q[2] - GOAL).abs() < 1e-9);
+        //a single update moves alpha of the way from 0
+        let (mut w, a_info) = chain();
+        let mut agent = Agent::new(&a_info, 0);
+        let mut pi = Policy::new(&w);
+        pi.fill_random(&mut w, &mut agent);
+        let mut g = Graph::new(&mut pi, &a_info, &w);
+        g.fill_q(&w);
+        g.q[(3, 0)].iter_mut().for_each(|q| *q = 100.0);
+        g.update(&(2, 0), &RIGHT, GOAL, None, &c_info);
+        assert_eq!(g.q[(2, 0)][RIGHT], c_info.alpha * GOAL);
+    }
+}
@@ -0,0 +1,91 @@
+use std::error::Error;
+use plotters::{prelude::*, coord::Shift};
+
+//Svg is only picked by the figure switches
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Backend {
+    Png,
+    Svg,
+}
+
+pub trait Figure {
+    //k:frame index when animated, 0 otherwise
+    fn draw<DB:DrawingBackend>(&self, k:usize, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+        where DB::ErrorType: 'static;
+}
+
+impl Backend {
+    //writes {name}.png or {name}.svg
+    pub fn render(&self, name:&str, size:(u32, u32), k:usize, fig:&impl Figure) -> Result<(), Box<dyn Error>> {
+        match self {
+            Backend::Png => {
+                let file = format!("{}.png", name);
+                let canvas = BitMapBackend::new(&file, size).into_drawing_area();
+                draw_on(&canvas, k, fig)
+            },
+            Backend::Svg => {
+                let file = format!("{}.svg", name);
+                let canvas = SVGBackend::new(&file, size).into_drawing_area();
+                draw_on(&canvas, k, fig)
+            },
+        }
+    }
+}
+
+//rgb pixels, row by row, for snapshot tests and embedding without a file
+#[allow(dead_code)]
+pub fn render_buffer(size:(u32, u32), k:usize, fig:&impl Figure) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut buffer = vec![0; size.0 as usize * size.1 as usize * 3];
+    {
+        let canvas = BitMapBackend::with_buffer(&mut buffer, size).into_drawing_area();
+        draw_on(&canvas, k, fig)?;
+    }
+    Ok(buffer)
+}
+
+//works on any backend, e.g. BitMapBackend::with_buffer for in-memory images
+pub fn draw_on<DB:DrawingBackend>(canvas:&DrawingArea<DB, Shift>, k:usize, fig:&impl Figure) -> Result<(), Box<dyn Error>>
+    where DB::ErrorType: 'static {
+    canvas.fill(&WHITE)?;
+    fig.draw(k, canvas)?;
+    canvas.present()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a red square in the middle, shifted right by k cells
+    struct Square;
+
+    impl Figure for Square {
+        fn draw<DB:DrawingBackend>(&self, k:usize, canvas:&DrawingArea<DB, Shift>) -> Result<(), Box<dyn Error>>
+            where DB::ErrorType: 'static {
+            let mut chart = ChartBuilder::on(canvas).build_cartesian_2d(0.0..4.0, 0.0..4.0)?;
+            let x = k as f64;
+            chart.draw_series([Rectangle::new([(x + 1.0, 1.0), (x + 3.0, 3.0)], RED.filled())])?;
+            Ok(())
+        }
+    }
+
+    fn pixel(buffer:&[u8], w:usize, p:(usize, usize)) -> &[u8] {
+        let i = (p.1 * w + p.0) * 3;
+        &buffer[i..i + 3]
+    }
+
+    #[test]
+    fn render_into_memory() {
+        let buffer = render_buffer((40, 40), 0, &Square).unwrap();
+        assert_eq!(buffer.len(), 40 * 40 * 3);
+        assert_eq!(pixel(&buffer, 40, (2, 2)), [255, 255, 255]);
+        assert_eq!(pixel(&buffer, 40, (20, 20)), [255, 0, 0]);
+        assert_eq!(pixel(&buffer, 40, (37, 20)), [255, 255, 255]);
+        //same figure, same pixels
+        assert_eq!(render_buffer((40, 40), 0, &Square).unwrap(), buffer);
+        //the frame index reaches the figure
+        let moved = render_buffer((40, 40), 1, &Square).unwrap();
+        assert_eq!(pixel(&moved, 40, (37, 20)), [255, 0, 0]);
+    }
+}
@@ -1,6 +1,10 @@
 
 mod nd_vec;
+mod figure;
+mod curve;
+mod animation;
 mod poisson;
+mod racetrack;
 mod exercise4_7;
 mod exercise4_9;
 mod exercise5_12;
@@ -21,6 +25,6 @@ fn main() {
     };
     match ret {
         Ok(_) => {},
-        Err(e) => { println!("{}", e.to_string()) }
+        Err(e) => { println!("{}", e) }
     };
 }
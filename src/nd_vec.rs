@@ -4,6 +4,7 @@ type Vec2 = (i32, i32);
 
 pub struct NdVec1<T> {
     pub offset: i32,
+    #[allow(dead_code)]
     pub dimension: i32,
     pub data: Vec<T>,
 }
@@ -24,7 +25,7 @@ impl<T> NdVec1<T> {
         self.data.push(v);
     }
 
-    pub fn iter(&self) -> std::slice::Iter<T> {
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
         self.data.iter()
     }
 }
@@ -86,11 +87,11 @@ impl<T> NdVec2<T> {
         self.data.push(v);
     }
 
-    pub fn iter(&self) -> std::slice::Iter<T> {
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
         self.data.iter()
     }
 
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<T> {
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
         self.data.iter_mut()
     }
 }
@@ -3,6 +3,7 @@
 pub struct Poisson {
     pmf_v: Vec<f64>,
     cdf_v: Vec<f64>,
+    #[allow(dead_code)]
     pub l:usize,
 }
 
@@ -10,6 +11,8 @@ impl Poisson {
     pub fn new(l:usize, range:usize) -> Self {
         let mut pmf_v:Vec<f64> = Vec::new();
         let mut cdf_v:Vec<f64> = Vec::new();
+        //kept as written, the exact constant shifts the 4.7 results slightly
+        #[allow(clippy::approx_constant)]
         let e:f64 = 2.7182818284;
         let lf = l as f64;
         let mut n_rank:f64 = 1.0;
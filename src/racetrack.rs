@@ -0,0 +1,437 @@
+use std::cmp::{ min, max };
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+use crate::nd_vec::NdVec2;
+
+pub type Vec2 = (i32, i32);
+pub type State = (Vec2, Vec2);//(position, velocity)
+pub type Action = Vec2;//velocity increments
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Cell {
+    Wall,
+    Track,
+    Start,
+    Finish,
+}
+
+pub struct Field {
+    pub cell:NdVec2<Cell>,//index:(x, y), y:0 at the start side
+    pub start:Vec<Vec2>,
+    pub corner:i32,
+    boundary:Vec<Vec2>,//setup rows, index:y, value:(x_min, x_max)
+}
+
+#[derive(Clone, Copy)]
+pub struct TrackInfo {
+    pub velocity_max:i32,
+    pub step_reward:f64,
+    pub crash_reward:f64,//on top of step_reward
+    pub crash_reset:bool,//true:back to a start cell and go on, false:the episode ends
+    pub p_vel_inc0:f64,//chance that both velocity increments are 0, ignored when standing still
+}
+
+pub struct Racetrack<'a> {
+    pub field:&'a Field,
+    pub info:&'a TrackInfo,
+    pub position:Vec2,
+    pub velocity:Vec2,
+    pub crash:usize,//crashes since reset
+    rng:StdRng,
+}
+
+impl Field {
+    pub fn new() -> Self {
+        Self { cell:NdVec2::new((0, 0), (0, 0)), start:Vec::new(), corner:0, boundary:Vec::new() }
+    }
+
+    fn append_row(&mut self, x_range:(i32, i32), y_range:i32) -> &mut Self {
+        for _ in 0..y_range {
+            self.boundary.push(x_range);
+        }
+        self
+    }
+
+    //rows appended so far become track, the first row is the start line,
+    //rows reaching finish_line get a finish cell right after it
+    fn build(&mut self, finish_line:i32) {
+        let size = ((finish_line + 2) as usize, self.boundary.len());
+        self.cell = NdVec2::from_size(size);
+        self.cell.fill(Cell::Wall);
+        for (y, (x_min, x_max)) in self.boundary.iter().enumerate() {
+            let y = y as i32;
+            let c = if y == 0 { Cell::Start } else { Cell::Track };
+            for x in *x_min..=*x_max {
+                self.cell[(x, y)] = c;
+            }
+            if *x_max == finish_line {
+                self.cell[(finish_line + 1, y)] = Cell::Finish;
+            }
+        }
+        self.boundary.clear();
+        self.start = self.cells(Cell::Start);
+        self.corner = self.finish_corner();
+    }
+
+    pub fn setup_v1(&mut self) {
+        self.append_row((3, 8), 3)
+            .append_row((2, 8), 6)
+            .append_row((1, 8), 8)
+            .append_row((0, 8), 7)
+            .append_row((0, 9), 1)
+            .append_row((0, 16), 2)
+            .append_row((1, 16), 1)
+            .append_row((2, 16), 2)
+            .append_row((3, 16), 1);
+        self.build(16);
+    }
+
+    pub fn setup_v2(&mut self) {
+        self.append_row((0, 23), 3);
+        for k in 1..=15 {
+            self.append_row((k, 23), 1);
+        }
+        self.append_row((15, 24), 1)
+            .append_row((15, 26), 1)
+            .append_row((15, 27), 1)
+            .append_row((15, 30), 1)
+            .append_row((14, 32), 1)
+            .append_row((13, 32), 1)
+            .append_row((12, 32), 4)
+            .append_row((13, 32), 1)
+            .append_row((14, 32), 1)
+            .append_row((17, 32), 1);
+        self.build(32);
+    }
+
+    //'#' or ' ':wall, '.':track, 'S':start, 'F':finish, first line is the far end of the track
+    pub fn load(path:&str) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read track {}: {}", path, e))?;
+        Field::parse(&text)
+    }
+
+    //the text of a track file
+    pub fn parse(text:&str) -> Result<Self, Box<dyn Error>> {
+        let lines:Vec<&str> = text.trim_start_matches(['\r', '\n']).trim_end().lines().collect();
+        let w = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+        let mut f = Field::new();
+        f.cell = NdVec2::from_size((w, lines.len()));
+        f.cell.fill(Cell::Wall);
+        for (y, line) in lines.iter().rev().enumerate() {
+            for (x, c) in line.chars().enumerate() {
+                f.cell[(x as i32, y as i32)] = match c {
+                    '#' | ' ' => Cell::Wall,
+                    '.' => Cell::Track,
+                    'S' => Cell::Start,
+                    'F' => Cell::Finish,
+                    _ => return Err(format!("invalid track cell '{}' at line {} column {}", c, lines.len() - y, x + 1).into()),
+                };
+            }
+        }
+        f.start = f.cells(Cell::Start);
+        f.validate()?;
+        f.corner = f.finish_corner();
+        Ok(f)
+    }
+
+    //the row below the lowest finish cell, built and loaded tracks agree on it
+    fn finish_corner(&self) -> i32 {
+        self.cells(Cell::Finish).iter().map(|p| p.1).min().unwrap_or(0) - 1
+    }
+
+    pub fn cells(&self, c:Cell) -> Vec<Vec2> {
+        self.cell.iter().enumerate()
+            .filter(|(_, v)| **v == c)
+            .map(|(i, _)| self.cell.rev_index(i))
+            .collect()
+    }
+
+    pub fn get(&self, p:&Vec2) -> Cell {
+        let (w, h) = self.cell.dimension;
+        if p.0 < 0 || p.1 < 0 || p.0 >= w || p.1 >= h { Cell::Wall }
+        else { self.cell[p] }
+    }
+
+    //every track cell must be connected to the start, and some finish cell reachable,
+    //the finish ends a run so nothing is reached through it
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.start.is_empty() {
+            return Err("track has no start cell".into())
+        }
+        let mut visit:HashSet<Vec2> = self.start.iter().copied().collect();
+        let mut open:VecDeque<Vec2> = self.start.iter().copied().collect();
+        while let Some(p) = open.pop_front() {
+            if self.get(&p) == Cell::Finish { continue }
+            for d in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                let pp = (p.0 + d.0, p.1 + d.1);
+                if self.get(&pp) != Cell::Wall && visit.insert(pp) {
+                    open.push_back(pp);
+                }
+            }
+        }
+        let finish = self.cells(Cell::Finish);
+        if finish.is_empty() {
+            return Err("track has no finish cell".into())
+        }
+        if !finish.iter().any(|p| visit.contains(p)) {
+            return Err("finish is unreachable from start".into())
+        }
+        if let Some(p) = self.cells(Cell::Track).iter().find(|p| !visit.contains(p)) {
+            return Err(format!("track is disconnected at {:?}", p).into())
+        }
+        Ok(())
+    }
+
+    pub fn random_start(&self, rng:&mut StdRng) -> Vec2 {
+        *self.start.choose(rng).unwrap()
+    }
+
+    pub fn indexed_start(&self, r:usize) -> Vec2 {
+        self.start[r % self.start.len()]
+    }
+
+    pub fn is_outside(&self, p:&Vec2) -> (bool, bool) {
+        match self.get(p) {
+            Cell::Wall => (true, false),
+            Cell::Finish => (true, true),
+            _ => (false, false),
+        }
+    }
+
+    //walks every cell the segment p_s->p passes through (supercover) in order,
+    //stops at the first wall or finish cell, finish wins when both are touched at a corner
+    pub fn intersect(&self, p_s:&Vec2, p:&Vec2) -> (bool, bool) {
+        let (dx, dy) = (p.0 - p_s.0, p.1 - p_s.1);
+        let (sx, sy) = (dx.signum(), dy.signum());
+        let (nx, ny) = (dx.abs(), dy.abs());
+        let (mut ix, mut iy) = (0, 0);
+        let mut c = *p_s;
+        while ix < nx || iy < ny {
+            //which cell edge the segment crosses next, scaled by 2 * nx * ny
+            let d = (1 + 2 * ix) * ny - (1 + 2 * iy) * nx;
+            if d == 0 {
+                //exactly through a corner, both neighbours are touched
+                let side = [self.is_outside(&(c.0 + sx, c.1)), self.is_outside(&(c.0, c.1 + sy))];
+                if side.iter().any(|r| r.1) { return (true, true) }
+                if side.iter().any(|r| r.0) { return (true, false) }
+                c = (c.0 + sx, c.1 + sy);
+                ix += 1;
+                iy += 1;
+            }
+            else if d < 0 {
+                c.0 += sx;
+                ix += 1;
+            }
+            else {
+                c.1 += sy;
+                iy += 1;
+            }
+            let result = self.is_outside(&c);
+            if result.0 { return result }
+        }
+        (false, false)
+    }
+
+    pub fn print(&self) {
+        let (w, h) = self.cell.dimension;
+        for y in (0..h).rev() {
+            for x in 0..w {
+                match self.cell[(x, y)] {
+                    Cell::Wall => print!("       "),
+                    Cell::Finish => print!("|  F  |"),
+                    _ => print!("|{:02},{:02}|", x, y),
+                }
+            }
+            println!();
+        }
+    }
+}
+
+impl<'a> Racetrack<'a> {
+    pub fn new(field:&'a Field, info:&'a TrackInfo, seed:u64) -> Self {
+        Self { field, info, position:(0, 0), velocity:(0, 0), crash:0, rng:StdRng::seed_from_u64(seed) }
+    }
+
+    pub fn state(&self) -> State {
+        (self.position, self.velocity)
+    }
+
+    pub fn reset(&mut self) -> State {
+        let p = self.field.random_start(&mut self.rng);
+        self.reset_at(p)
+    }
+
+    pub fn reset_at(&mut self, p:Vec2) -> State {
+        self.reset_to((p, (0, 0)))
+    }
+
+    //any state, e.g. for exploring starts
+    pub fn reset_to(&mut self, s:State) -> State {
+        (self.position, self.velocity) = s;
+        self.crash = 0;
+        s
+    }
+
+    //returns (next state, reward, done), done when the finish line is crossed,
+    //or on a crash without crash_reset
+    pub fn step(&mut self, a:&Action) -> (State, f64, bool) {
+        let info = self.info;
+        let r:f64 = self.rng.gen();
+        let a = if r < info.p_vel_inc0 && self.velocity != (0, 0) { (0, 0) } else { *a };
+        let p_s = self.position;
+        let v_max = info.velocity_max;
+        let v = &mut self.velocity;
+        v.0 = min(max(v.0 + a.0, -v_max), v_max);
+        v.1 = min(max(v.1 + a.1, -v_max), v_max);
+        self.position = (p_s.0 + v.0, p_s.1 + v.1);
+        let (outside, finish) = self.field.intersect(&p_s, &self.position);
+        let mut reward = info.step_reward;
+        if outside && !finish {
+            reward += info.crash_reward;
+            self.crash += 1;
+            if !info.crash_reset { return (self.state(), reward, true) }
+            self.position = self.field.random_start(&mut self.rng);
+            self.velocity = (0, 0);
+        }
+        (self.state(), reward, finish)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same(f:&Field, g:&Field) {
+        assert_eq!(f.cell.dimension, g.cell.dimension);
+        assert!(f.cell.iter().zip(g.cell.iter()).all(|(a, b)| a == b));
+        assert_eq!(f.start, g.start);
+        assert_eq!(f.cells(Cell::Finish), g.cells(Cell::Finish));
+        assert_eq!(f.corner, g.corner);
+    }
+
+    fn open_field(w:usize, h:usize) -> Field {
+        let mut f = Field::new();
+        f.cell = NdVec2::from_size((w, h));
+        f.cell.fill(Cell::Track);
+        f
+    }
+
+    //cells whose closed square the segment between the centers of p_s and p touches, p_s left out
+    fn supercover(f:&Field, p_s:Vec2, p:Vec2) -> HashSet<Vec2> {
+        let d = ((p.0 - p_s.0) as f64, (p.1 - p_s.1) as f64);
+        let span = |o:i32, d:f64, c:i32| -> (f64, f64) {
+            let (lo, hi) = (c as f64 - 0.5 - o as f64, c as f64 + 0.5 - o as f64);
+            if d == 0.0 { if lo <= 0.0 && hi >= 0.0 { (0.0, 1.0) } else { (1.0, 0.0) } }
+            else { (f64::min(lo / d, hi / d), f64::max(lo / d, hi / d)) }
+        };
+        f.cells(Cell::Track).into_iter()
+            .filter(|c| *c != p_s)
+            .filter(|c| {
+                let (x0, x1) = span(p_s.0, d.0, c.0);
+                let (y0, y1) = span(p_s.1, d.1, c.1);
+                x0.max(y0).max(0.0) <= x1.min(y1).min(1.0) + 1e-12
+            })
+            .collect()
+    }
+
+    //every sign combination, axis moves and exact diagonals
+    const MOVE:[Vec2; 16] = [(3, 1), (3, -1), (-3, 1), (-3, -1), (1, 3), (1, -3), (-1, 3), (-1, -3),
+        (4, 0), (-4, 0), (0, 4), (0, -4), (2, 2), (2, -2), (-2, 2), (-2, -2)];
+
+    #[test]
+    fn intersect_walks_supercover() {
+        let mut f = open_field(13, 13);
+        let p_s = (6, 6);
+        for d in MOVE.iter().chain([(5, 3), (-2, 5), (0, 0)].iter()) {
+            let p = (p_s.0 + d.0, p_s.1 + d.1);
+            let cover = supercover(&f, p_s, p);
+            assert_eq!(f.intersect(&p_s, &p), (false, false), "open field {:?}", d);
+            for c in f.cells(Cell::Track).into_iter().filter(|c| *c != p_s) {
+                f.cell[c] = Cell::Wall;
+                assert_eq!(f.intersect(&p_s, &p), (cover.contains(&c), false), "move {:?} wall {:?}", d, c);
+                f.cell[c] = Cell::Finish;
+                assert_eq!(f.intersect(&p_s, &p), (cover.contains(&c), cover.contains(&c)), "move {:?} finish {:?}", d, c);
+                f.cell[c] = Cell::Track;
+            }
+        }
+    }
+
+    #[test]
+    fn intersect_stops_at_first_cell() {
+        let p_s = (6, 6);
+        for d in [(4, 2), (4, -2), (-4, 2), (-4, -2), (4, 0), (-4, 0), (0, 4), (0, -4)] {
+            let mid = (p_s.0 + d.0 / 2, p_s.1 + d.1 / 2);
+            let p = (p_s.0 + d.0, p_s.1 + d.1);
+            let mut f = open_field(13, 13);
+            f.cell[mid] = Cell::Wall;
+            f.cell[p] = Cell::Finish;
+            assert_eq!(f.intersect(&p_s, &p), (true, false), "wall before finish {:?}", d);
+            f.cell[mid] = Cell::Finish;
+            f.cell[p] = Cell::Wall;
+            assert_eq!(f.intersect(&p_s, &p), (true, true), "finish before wall {:?}", d);
+        }
+        //outside the grid is wall
+        let f = open_field(5, 5);
+        assert_eq!(f.intersect(&(4, 2), &(6, 2)), (true, false));
+        assert_eq!(f.intersect(&(2, 0), &(2, -1)), (true, false));
+    }
+
+    #[test]
+    fn intersect_exact_corner() {
+        let (p_s, p) = ((6, 6), (7, 7));
+        for (side, result) in [((Cell::Track, Cell::Track), (false, false)), ((Cell::Wall, Cell::Track), (true, false)),
+            ((Cell::Track, Cell::Wall), (true, false)), ((Cell::Wall, Cell::Finish), (true, true)),
+            ((Cell::Finish, Cell::Wall), (true, true)), ((Cell::Wall, Cell::Wall), (true, false))] {
+            let mut f = open_field(13, 13);
+            f.cell[(7, 6)] = side.0;
+            f.cell[(6, 7)] = side.1;
+            assert_eq!(f.intersect(&p_s, &p), result, "{:?}", result);
+        }
+        //the diagonal cell itself after two open neighbours
+        let mut f = open_field(13, 13);
+        f.cell[p] = Cell::Finish;
+        assert_eq!(f.intersect(&p_s, &p), (true, true));
+        //down-left through a corner
+        let mut f = open_field(13, 13);
+        f.cell[(5, 6)] = Cell::Wall;
+        assert_eq!(f.intersect(&p_s, &(5, 5)), (true, false));
+    }
+
+    #[test]
+    fn track_files_match_setup() {
+        let mut f = Field::new();
+        f.setup_v1();
+        assert_same(&Field::load("track/v1.txt").unwrap(), &f);
+        let mut f = Field::new();
+        f.setup_v2();
+        assert_same(&Field::load("track/v2.txt").unwrap(), &f);
+    }
+
+    fn parse_error(text:&str) -> String {
+        match Field::parse(text) {
+            Ok(_) => panic!("track parsed:\n{}", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_rejects_invalid_track() {
+        assert!(Field::parse("#..F\n#..F\n#S.#").is_ok());
+        assert_eq!(parse_error("#..F\n#..F\n#..#"), "track has no start cell");
+        assert_eq!(parse_error("#...\n#...\n#S.#"), "track has no finish cell");
+        assert_eq!(parse_error("#..#F\n#..#F\n#S.##"), "finish is unreachable from start");
+        assert_eq!(parse_error("#..F#.\n#..F#.\n#S.#.."), "track is disconnected at (4, 0)");
+        assert_eq!(parse_error("#..F\n#..X\n#S.#"), "invalid track cell 'X' at line 2 column 4");
+    }
+
+    #[test]
+    fn parse_does_not_pass_through_finish() {
+        //the right column is reached only across the finish
+        assert_eq!(parse_error("#..F.\n#..F.\n#S.##"), "track is disconnected at (4, 1)");
+    }
+}